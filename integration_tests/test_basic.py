@@ -1,3 +1,7 @@
+import json
+import socket
+import time
+
 import credis
 import pytest
 
@@ -68,5 +72,1049 @@ def test(redis_port):
     assert time == "1564390680"
 
 
+def test_insertmap(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "insertmap")
+    assert (
+        conn.execute(
+            "agg.new", "insertmap", "time", "price", "amount", "default", 0
+        )
+        == "OK"
+    )
+    assert (
+        conn.execute(
+            "agg.view", "insertmap", "insertmap_view", "count", "price", "sum", "amount"
+        )
+        == "OK"
+    )
+    assert (
+        conn.execute(
+            "agg.insertmap", "insertmap", "1564390729000", "amount", 1.5, "price", 10
+        )
+        == "1564390729000-0"
+    )
+    conn.execute("agg.insertmap", "insertmap", "1564390729000", "price", 11)
+    conn.execute("agg.insertmap", "insertmap", "1564390729000", "amount", 2)
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.insertmap", "insertmap", "1564390729000", "fee", 1)
+    name, values, time = conn.execute("agg.current", "insertmap")
+    assert name == "insertmap_view"
+    assert tuple(values) == ("2", "3.5")
+    assert time is None
+
+
+def test_minsert(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "minsert")
+    assert conn.execute("agg.new", "minsert", "time", "price") == "OK"
+    assert (
+        conn.execute("agg.view", "minsert", "minsert_view", "sum", "price") == "OK"
+    )
+    ids = conn.execute(
+        "agg.minsert", "minsert", "1564390729000", 1, "1564390729000", 2, "1564390730000", 3
+    )
+    assert tuple(ids) == ("1564390729000-0", "1564390729000-1", "1564390730000-0")
+    # the whole batch is rejected if any row is invalid
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.minsert", "minsert", "1564390731000", 1, "1564390729000", 2)
+    assert conn.execute("agg.last_id", "minsert") == "1564390730000-0"
+    _, values, _ = conn.execute("agg.current", "minsert")
+    assert tuple(values) == ("6",)
+
+
+def test_source(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "source", "source:stream")
+    assert conn.execute("agg.new", "source", "time", "price", "amount") == "OK"
+    assert (
+        conn.execute(
+            "agg.view", "source", "source_view", "sum", "price", "count", "amount"
+        )
+        == "OK"
+    )
+    conn.execute("xadd", "source:stream", "1564390729000-0", "price", 1, "amount", 2)
+    assert (
+        conn.execute(
+            "agg.source", "source", "stream", "source:stream", "fields", "price", "amount"
+        )
+        == "OK"
+    )
+    assert conn.execute("agg.last_id", "source") == "1564390729000-0"
+    conn.execute("xadd", "source:stream", "1564390730000-0", "price", 2, "fee", 1)
+    assert conn.execute("agg.last_id", "source") == "1564390730000-0"
+    _, values, _ = conn.execute("agg.current", "source")
+    assert tuple(values) == ("3", "1")
+    # the table can't consume what its views emit
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute(
+            "agg.view",
+            "source",
+            "source_kline",
+            "interval",
+            60,
+            "stream",
+            "source:stream",
+            "max",
+            "price",
+        )
+
+
+def test_stream_output(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "stream_output", "stream_output:kline")
+    assert conn.execute("agg.new", "stream_output", "time", "price") == "OK"
+    assert (
+        conn.execute(
+            "agg.view",
+            "stream_output",
+            "stream_output_view",
+            "interval",
+            60,
+            "stream",
+            "stream_output:kline",
+            "maxlen",
+            10,
+            "first",
+            "price",
+            "max",
+            "price",
+        )
+        == "OK"
+    )
+    conn.execute("agg.insert", "stream_output", "1564390729000", 2)
+    conn.execute("agg.insert", "stream_output", "1564390730000", 3)
+    # nothing is emitted until the bucket is finalized
+    assert conn.execute("xlen", "stream_output:kline") == 0
+    conn.execute("agg.insert", "stream_output", "1564390741000", 1)
+    ((id, fields),) = conn.execute("xrange", "stream_output:kline", "-", "+")
+    assert id == "1564390680000-0"
+    assert tuple(fields) == ("first_price", "2", "max_price", "3")
+
+
+def test_zset_output(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "zset_output", "zset_output_view")
+    assert conn.execute("agg.new", "zset_output", "time", "price") == "OK"
+    assert (
+        conn.execute(
+            "agg.view",
+            "zset_output",
+            "zset_output_view",
+            "interval",
+            60,
+            "output",
+            "zset",
+            "max",
+            "price",
+        )
+        == "OK"
+    )
+    conn.execute("agg.insert", "zset_output", "1564390729000", 2)
+    assert conn.execute("agg.save", "zset_output") == "OK"
+    conn.execute("agg.insert", "zset_output", "1564390730000", 3)
+    conn.execute("agg.insert", "zset_output", "1564390741000", 1)
+    assert conn.execute("agg.save", "zset_output") == "OK"
+    members = conn.execute("zrangebyscore", "zset_output_view", "-inf", "+inf")
+    assert tuple(members) == ("1564390680:[3.0]", "1564390740:[1.0]")
+
+
+def test_format(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "format", "format_object", "format_hash:1564390680")
+    assert conn.execute("agg.new", "format", "time", "price") == "OK"
+    assert (
+        conn.execute(
+            "agg.view",
+            "format",
+            "format_object",
+            "interval",
+            60,
+            "format",
+            "object",
+            "first",
+            "price",
+            "as",
+            "open",
+            "max",
+            "price",
+        )
+        == "OK"
+    )
+    assert (
+        conn.execute(
+            "agg.view",
+            "format",
+            "format_hash",
+            "interval",
+            60,
+            "format",
+            "hash",
+            "first",
+            "price",
+            "as",
+            "open",
+            "max",
+            "price",
+        )
+        == "OK"
+    )
+    conn.execute("agg.insert", "format", "1564390729000", 2)
+    conn.execute("agg.insert", "format", "1564390730000", 3)
+    assert conn.execute("agg.save", "format") == "OK"
+    assert (
+        conn.execute("hget", "format_object", "1564390680")
+        == '{"open":2.0,"max_price":3.0}'
+    )
+    assert conn.execute("hget", "format_hash:1564390680", "open") == "2"
+    assert conn.execute("hget", "format_hash:1564390680", "max_price") == "3"
+
+
+def test_alias(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "alias")
+    assert conn.execute("agg.new", "alias", "time", "price", "amount") == "OK"
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.view", "alias", "alias_view", "sum", "price", "sum", "price")
+    assert (
+        conn.execute(
+            "agg.view",
+            "alias",
+            "alias_view",
+            "sum",
+            "price",
+            "sum",
+            "price",
+            "as",
+            "total",
+            "max",
+            "amount",
+        )
+        == "OK"
+    )
+    conn.execute("agg.insert", "alias", "1564390729000", 2, 1)
+    name, values, _ = conn.execute("agg.current", "alias", "withnames")
+    assert name == "alias_view"
+    assert tuple(values) == ("sum_price", "2", "total", "2", "max_amount", "1")
+
+
+def test_legacy_field_names(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "legacy", "legacy_restore")
+    assert conn.execute("agg.new", "legacy", "time", "price") == "OK"
+    assert conn.execute("agg.view", "legacy", "legacy_view", "sum", "price") == "OK"
+    [[_, _, [op]]] = json.loads(conn.execute("agg.export", "legacy"))
+    # the views created before the aliases have no field names
+    state = {
+        "fields": ["time", "price"],
+        "views": [
+            {
+                "name": "legacy_view",
+                "fields": [[1, op, ""], [1, op, ""]],
+                "groupby": None,
+            }
+        ],
+        "last_id": [0, 0],
+    }
+    assert conn.execute("agg.restore", "legacy_restore", json.dumps(state)) == "OK"
+    _, values, _ = conn.execute("agg.current", "legacy_restore", "withnames")
+    assert tuple(values[::2]) == ("sum_price", "sum_price_1")
+
+
+def test_retention(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "retention", "retention_hash", "retention_zset")
+    assert conn.execute("agg.new", "retention", "time", "price") == "OK"
+    assert (
+        conn.execute(
+            "agg.view",
+            "retention",
+            "retention_hash",
+            "interval",
+            60,
+            "retention",
+            "3m",
+            "max",
+            "price",
+        )
+        == "OK"
+    )
+    assert (
+        conn.execute(
+            "agg.view",
+            "retention",
+            "retention_zset",
+            "interval",
+            60,
+            "output",
+            "zset",
+            "maxbuckets",
+            2,
+            "max",
+            "price",
+        )
+        == "OK"
+    )
+    for i in range(5):
+        conn.execute("agg.insert", "retention", str((1564390680 + i * 60) * 1000), i)
+    assert sorted(conn.execute("hkeys", "retention_hash")) == [
+        "1564390800",
+        "1564390860",
+    ]
+    assert tuple(conn.execute("zrange", "retention_zset", 0, -1)) == (
+        "1564390800:[2.0]",
+        "1564390860:[3.0]",
+    )
+
+
+def test_rollup(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "rollup")
+    assert conn.execute("agg.new", "rollup", "time", "price") == "OK"
+    assert (
+        conn.execute(
+            "agg.view",
+            "rollup",
+            "rollup_1m",
+            "interval",
+            60,
+            "first",
+            "price",
+            "as",
+            "open",
+            "max",
+            "price",
+            "count",
+            "price",
+        )
+        == "OK"
+    )
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.view", "rollup", "rollup_0", "interval", 0, "max", "price")
+    with pytest.raises(credis.base.RedisReplyError):
+        # interval is not a multiple of the source
+        conn.execute(
+            "agg.view",
+            "rollup",
+            "rollup_5m",
+            "interval",
+            90,
+            "from",
+            "rollup_1m",
+            "first",
+            "open",
+        )
+    with pytest.raises(credis.base.RedisReplyError):
+        # no such field in the source
+        conn.execute(
+            "agg.view",
+            "rollup",
+            "rollup_5m",
+            "interval",
+            300,
+            "from",
+            "rollup_1m",
+            "min",
+            "price",
+        )
+    assert (
+        conn.execute(
+            "agg.view",
+            "rollup",
+            "rollup_5m",
+            "interval",
+            300,
+            "from",
+            "rollup_1m",
+            "first",
+            "open",
+            "max",
+            "price",
+            "count",
+            "price",
+        )
+        == "OK"
+    )
+    for time, price in [(800, 1), (830, 5), (860, 2), (920, 3)]:
+        conn.execute("agg.insert", "rollup", str((1564390000 + time) * 1000), price)
+    _, _, _, name, values, time = conn.execute("agg.current", "rollup")
+    assert name == "rollup_5m"
+    assert tuple(values) == ("1", "5", "3")
+    assert time == "1564390800"
+
+
+def test_merge(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    names = ["merge", "merge:a", "merge:b", "merge:c"]
+    conn.execute("del", *names)
+    for name in names:
+        assert conn.execute("agg.new", name, "time", "price") == "OK"
+        assert (
+            conn.execute(
+                "agg.view",
+                name,
+                name + ":view",
+                "interval",
+                60,
+                "max",
+                "price",
+                "avg",
+                "price",
+                "stdp",
+                "price",
+            )
+            == "OK"
+        )
+    conn.execute("agg.insert", "merge:a", "1564390729000", 1)
+    conn.execute("agg.insert", "merge:a", "1564390730000", 3)
+    conn.execute("agg.insert", "merge:b", "1564390731000", 5)
+    # older bucket is ignored
+    conn.execute("agg.insert", "merge:c", "1564390500000", 100)
+    # the state of dest is kept
+    conn.execute("agg.insert", "merge", "1564390732000", 3)
+    assert conn.execute("agg.merge", "merge", "merge:a", "merge:b", "merge:c") == "OK"
+    _, values, time = conn.execute("agg.current", "merge")
+    assert time == "1564390680"
+    assert tuple(values[:2]) == ("5", "3")
+    assert abs(float(values[2]) - 2 ** 0.5) < 1e-9
+    assert conn.execute("agg.last_id", "merge") == "1564390732000-0"
+
+    state = conn.execute("agg.export", "merge")
+    assert conn.execute("agg.import", "merge:c", state) == "OK"
+    assert conn.execute("agg.current", "merge:c")[1:] == conn.execute(
+        "agg.current", "merge"
+    )[1:]
+
+    # first and last are merged by the time, not by the order of the tables
+    names = ["merge_kline", "merge_kline:a", "merge_kline:b"]
+    conn.execute("del", *names)
+    for name in names:
+        assert conn.execute("agg.new", name, "time", "price") == "OK"
+        assert (
+            conn.execute(
+                "agg.view",
+                name,
+                name + ":view",
+                "interval",
+                60,
+                "first",
+                "price",
+                "last",
+                "price",
+            )
+            == "OK"
+        )
+    rows = [("a", 729, 1), ("a", 735, 4), ("b", 730, 2), ("b", 733, 3)]
+    for name, time, price in rows:
+        time = str((1564390000 + time) * 1000)
+        conn.execute("agg.insert", "merge_kline:" + name, time, price)
+    assert conn.execute("agg.merge", "merge_kline", *names[1:]) == "OK"
+    _, values, time = conn.execute("agg.current", "merge_kline")
+    assert time == "1564390680"
+    assert tuple(values) == ("1", "4")
+
+    # the older bucket of dest is merged into the rollup views
+    names = ["merge_rollup", "merge_rollup:a"]
+    conn.execute("del", *names, *[name + ":1m" for name in names])
+    for name in names:
+        assert conn.execute("agg.new", name, "time", "price") == "OK"
+        conn.execute("agg.view", name, name + ":1m", "interval", 60, "max", "price")
+        conn.execute(
+            "agg.view",
+            name,
+            name + ":5m",
+            "interval",
+            300,
+            "from",
+            name + ":1m",
+            "max",
+            "price",
+        )
+    conn.execute("agg.insert", "merge_rollup", "1564390680000", 7)
+    conn.execute("agg.insert", "merge_rollup:a", "1564390740000", 2)
+    assert conn.execute("agg.merge", "merge_rollup", "merge_rollup:a") == "OK"
+    _, values_1m, time_1m, _, values_5m, time_5m = conn.execute(
+        "agg.current", "merge_rollup"
+    )
+    assert (tuple(values_1m), time_1m) == (("2",), "1564390740")
+    assert (tuple(values_5m), time_5m) == (("7",), "1564390500")
+
+
+def test_rebuild(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "rebuild", "rebuild_view")
+    assert conn.execute("agg.new", "rebuild", "time", "price", "retain", "100s") == "OK"
+    for time, price in [(0, 1), (30, 2), (60, 3), (130, 4), (150, 5)]:
+        conn.execute("agg.insert", "rebuild", str((1564390680 + time) * 1000), price)
+    assert (
+        conn.execute(
+            "agg.view", "rebuild", "rebuild_view", "interval", 60, "sum", "price"
+        )
+        == "OK"
+    )
+    assert conn.execute("agg.current", "rebuild")[2] is None
+    assert conn.execute("agg.rebuild", "rebuild", "rebuild_view") == "OK"
+    # the first two rows are out of the retention
+    assert conn.execute("hget", "rebuild_view", "1564390740") == "[3.0]"
+    assert conn.execute("hget", "rebuild_view", "1564390800") == "[9.0]"
+    _, values, time = conn.execute("agg.current", "rebuild")
+    assert tuple(values) == ("9",)
+    assert time == "1564390800"
+
+
+def test_rebuild_outputs(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute(
+        "del", "rebuild_outputs", "rebuild_1m", "rebuild_2m", "rebuild_1m:1564390000"
+    )
+    assert (
+        conn.execute("agg.new", "rebuild_outputs", "time", "price", "retain", "1h")
+        == "OK"
+    )
+    for time, price in [(0, 1), (60, 2), (150, 3)]:
+        conn.execute(
+            "agg.insert", "rebuild_outputs", str((1564390680 + time) * 1000), price
+        )
+    conn.execute(
+        "agg.view",
+        "rebuild_outputs",
+        "rebuild_1m",
+        "interval",
+        60,
+        "format",
+        "hash",
+        "sum",
+        "price",
+    )
+    conn.execute(
+        "agg.view",
+        "rebuild_outputs",
+        "rebuild_2m",
+        "interval",
+        120,
+        "from",
+        "rebuild_1m",
+        "sum",
+        "price",
+    )
+    conn.execute("hset", "rebuild_1m:1564390000", "sum_price", 100)
+    assert conn.execute("agg.rebuild", "rebuild_outputs", "rebuild_1m") == "OK"
+    # the stale per-bucket hash is deleted
+    assert conn.execute("exists", "rebuild_1m:1564390000") == 0
+    assert conn.execute("hget", "rebuild_1m:1564390680", "sum_price") == "1"
+    assert conn.execute("hget", "rebuild_1m:1564390740", "sum_price") == "2"
+    # the rollup view is rebuilt from the buckets
+    assert conn.execute("hget", "rebuild_2m", "1564390680") == "[3.0]"
+
+
+def test_manage_views(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute(
+        "del",
+        "views",
+        "views_1m",
+        "views_min",
+        "views_5m",
+        "views_sum",
+        "views_taken",
+        "views_hash:1564390680",
+    )
+    assert conn.execute("agg.new", "views", "time", "price") == "OK"
+    # the output would overwrite the table
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.view", "views", "views", "max", "price")
+    conn.execute("agg.view", "views", "views_1m", "interval", 60, "max", "price")
+    conn.execute(
+        "agg.view", "views", "views_5m", "interval", 300, "from", "views_1m", "max", "price"
+    )
+    conn.execute("agg.view", "views", "views_sum", "sum", "price")
+    conn.execute(
+        "agg.view",
+        "views",
+        "views_hash",
+        "interval",
+        60,
+        "format",
+        "hash",
+        "max",
+        "price",
+    )
+    conn.execute("agg.insert", "views", "1564390729000", 2)
+    conn.execute("agg.save", "views")
+
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.dropview", "views", "views_1m")
+    conn.execute("set", "views_taken", 1)
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.renameview", "views", "views_1m", "views_taken")
+    # the view has no output yet
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.renameview", "views", "views_5m", "views_taken")
+    assert conn.execute("get", "views_taken") == "1"
+    # the per-bucket keys are named after the view
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.renameview", "views", "views_hash", "views_other")
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.renameview", "views", "views_1m", "views")
+    assert conn.execute("agg.renameview", "views", "views_1m", "views_min") == "OK"
+    assert conn.execute("hget", "views_min", "1564390680") == "[2.0]"
+    assert conn.execute("exists", "views_1m") == 0
+    assert conn.execute("agg.resetview", "views", "views_min") == "OK"
+    assert conn.execute("agg.dropview", "views", "views_sum", "deleteoutput") == "OK"
+    assert conn.execute("exists", "views_sum") == 0
+    result = conn.execute("agg.current", "views")
+    assert len(result) == 9
+    name, values, time = result[:3]
+    assert name == "views_min"
+    assert tuple(values) == (None,)
+    assert time is None
+    assert result[3] == "views_5m"
+
+
+def test_alter(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "alter")
+    assert conn.execute("agg.new", "alter", "time", "price", "amount") == "OK"
+    conn.execute("agg.view", "alter", "alter_view", "sum", "amount")
+    assert conn.execute("agg.alter", "alter", "add", "fee", "default", 0.5) == "OK"
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.alter", "alter", "add", "fee")
+    conn.execute("agg.view", "alter", "alter_fee", "sum", "fee")
+    # old producers don't know the new column
+    conn.execute("agg.insert", "alter", "1564390729000", 1, 2)
+    conn.execute("agg.insert", "alter", "1564390730000", 1, 3, 1)
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.insert", "alter", "1564390731000", 1)
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.alter", "alter", "drop", "amount")
+    assert conn.execute("agg.alter", "alter", "drop", "price") == "OK"
+    conn.execute("agg.insert", "alter", "1564390731000", 4)
+    result = conn.execute("agg.current", "alter")
+    assert tuple(result[1]) == ("9",)
+    assert tuple(result[4]) == ("2",)
+
+
+def test_info(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "info")
+    assert conn.execute("agg.new", "info", "time", "price", "default", 1) == "OK"
+    conn.execute(
+        "agg.view", "info", "info_view", "interval", 60, "max", "price", "as", "high"
+    )
+    conn.execute("agg.insert", "info", "1564390729000", 2)
+    conn.execute("agg.insert", "info", "1564390741000", 2)
+    info = conn.execute("agg.info", "info")
+    info = dict(zip(info[::2], info[1::2]))
+    assert tuple(map(tuple, info["columns"])) == (("time", None), ("price", "1"))
+    assert info["last_id"] == "1564390741000-0"
+    (view,) = info["views"]
+    view = dict(zip(view[::2], view[1::2]))
+    assert view["name"] == "info_view"
+    assert view["interval"] == 60
+    assert tuple(map(tuple, view["fields"])) == (("high", "max", "price"),)
+    assert view["output"] == "hash"
+    assert view["format"] == "array"
+    assert view["current"] == "1564390740"
+    assert view["rows"] == 2
+    assert view["late"] == 0
+    assert view["last_save"] > 0
+
+
+def test_list(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "list_a", "list_b", "list_c")
+    assert conn.execute("agg.new", "list_a", "time", "price") == "OK"
+    assert conn.execute("agg.new", "list_b", "time", "price", "volume") == "OK"
+    conn.execute("agg.view", "list_b", "list_view", "interval", 60, "max", "price")
+    assert tuple(map(tuple, conn.execute("agg.list", "match", "list_*"))) == (
+        ("list_a", 2, 0),
+        ("list_b", 3, 1),
+    )
+    conn.execute("rename", "list_a", "list_c")
+    conn.execute("del", "list_b")
+    assert tuple(map(tuple, conn.execute("agg.list", "match", "list_?"))) == (
+        ("list_c", 2, 0),
+    )
+    raw = credis.Connection(port=redis_port)
+    raw.execute("del", "list_d")
+    raw.execute("restore", "list_d", 0, raw.execute("dump", "list_c"))
+    assert tuple(map(tuple, conn.execute("agg.list", "match", "list_?"))) == (
+        ("list_c", 2, 0),
+        ("list_d", 2, 0),
+    )
+    conn.execute("select", 1)
+    conn.execute("del", "list_c")
+    conn.execute("select", 0)
+    assert conn.execute("move", "list_c", 1) == 1
+    assert tuple(map(tuple, conn.execute("agg.list", "match", "list_?"))) == (
+        ("list_d", 2, 0),
+    )
+    conn.execute("select", 1)
+    assert tuple(map(tuple, conn.execute("agg.list", "match", "list_?"))) == (
+        ("list_c", 2, 0),
+    )
+    conn.execute("del", "list_c")
+    conn.execute("select", 0)
+    conn.execute("del", "list_d")
+
+
+def test_range(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "range", "range_view", "range_zset")
+    assert conn.execute("agg.new", "range", "time", "price") == "OK"
+    conn.execute("agg.view", "range", "range_view", "interval", 60, "max", "price")
+    conn.execute(
+        "agg.view",
+        "range",
+        "range_zset",
+        "interval",
+        60,
+        "output",
+        "zset",
+        "format",
+        "object",
+        "min",
+        "price",
+    )
+    conn.execute("agg.insert", "range", "1564390629000", 1)
+    conn.execute("agg.insert", "range", "1564390689000", 2)
+    conn.execute("agg.insert", "range", "1564390749000", 3)
+    expected = (
+        ("1564390620", ("1",)),
+        ("1564390680", ("2",)),
+        ("1564390740", ("3",)),
+    )
+    for view in ("range_view", "range_zset"):
+        buckets = conn.execute("agg.range", "range", view, "-", "+")
+        assert tuple((t, tuple(v)) for t, v in buckets) == expected
+    buckets = conn.execute(
+        "agg.range", "range", "range_view", 1564390680, "+", "count", 1, "rev"
+    )
+    assert tuple((t, tuple(v)) for t, v in buckets) == expected[2:]
+    for view in ("range_view", "range_zset"):
+        buckets = conn.execute("agg.range", "range", view, "-", "+", "count", 2)
+        assert tuple((t, tuple(v)) for t, v in buckets) == expected[:2]
+        buckets = conn.execute("agg.range", "range", view, "-", "+", "count", 2, "rev")
+        assert tuple((t, tuple(v)) for t, v in buckets) == expected[:0:-1]
+        buckets = conn.execute("agg.range", "range", view, 1564390600, 1564390700)
+        assert tuple((t, tuple(v)) for t, v in buckets) == expected[:2]
+    # the missing buckets are skipped
+    conn.execute("agg.insert", "range", "1564390989000", 4)
+    buckets = conn.execute(
+        "agg.range", "range", "range_view", 1564390740, 1564391000, "count", 2
+    )
+    assert tuple((t, tuple(v)) for t, v in buckets) == (
+        ("1564390740", ("3",)),
+        ("1564390980", ("4",)),
+    )
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.range", "range", "no_view", "-", "+")
+
+
+def test_query(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "query", "query_view", "query_states")
+    assert conn.execute("agg.new", "query", "time", "price") == "OK"
+    conn.execute(
+        "agg.view",
+        "query",
+        "query_view",
+        "interval",
+        60,
+        "states",
+        "query_states",
+        "max",
+        "price",
+        "first",
+        "price",
+    )
+    conn.execute("agg.insert", "query", "1564390629000", 1)
+    conn.execute("agg.insert", "query", "1564390689000", 2)
+    conn.execute("agg.insert", "query", "1564390749000", 3)
+    buckets = conn.execute("agg.query", "query", "query_view", "interval", 120)
+    assert tuple((t, tuple(v)) for t, v in buckets) == (
+        ("1564390560", ("1", "1")),
+        ("1564390680", ("3", "2")),
+    )
+    buckets = conn.execute(
+        "agg.query", "query", "query_view", "interval", 120, "from", 1564390600
+    )
+    assert tuple(t for t, v in buckets) == ("1564390680",)
+    # the fields which aren't bucket times are skipped
+    conn.execute("hset", "query_states", "nan", "[]", "inf", "[]")
+    buckets = conn.execute("agg.query", "query", "query_view", "interval", 120)
+    assert tuple(t for t, v in buckets) == ("1564390560", "1564390680")
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.query", "query", "query_view", "interval", 90)
+
+
+def test_sql(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "sql", "sql_view")
+    assert conn.execute("agg.new", "sql", "time", "price", "retain", "1h") == "OK"
+    assert (
+        conn.execute(
+            "agg.sqlview",
+            "sql",
+            "CREATE VIEW sql_view AS SELECT time_bucket('1 minute', time) AS t, "
+            "max(price) AS high, count(*) FROM sql GROUP BY t",
+        )
+        == "OK"
+    )
+    conn.execute("agg.insert", "sql", "1564390629000", 1)
+    conn.execute("agg.insert", "sql", "1564390689000", 2)
+    conn.execute("agg.insert", "sql", "1564390699000", 4)
+    assert tuple(conn.execute("agg.current", "sql", "withnames")[1]) == (
+        "high",
+        "4",
+        "count_time",
+        "2",
+    )
+    buckets = conn.execute(
+        "agg.sql",
+        "sql",
+        "select time_bucket('2m', time), min(price) from sql "
+        "where time > 1564390629000 group by 1",
+    )
+    assert tuple((t, tuple(v)) for t, v in buckets) == (("1564390680", ("2",)),)
+    assert tuple(conn.execute("agg.sql", "sql", "SELECT sum(price) FROM sql")) == (
+        "7",
+    )
+    for command, statement in (
+        ("agg.sql", "SELECT time_bucket('1m', price), max(price) FROM sql GROUP BY 1"),
+        ("agg.sql", "SELECT sum(price) FROM sql_view"),
+        ("agg.sql", "CREATE VIEW sql_sum AS SELECT sum(price) FROM sql"),
+        ("agg.sqlview", "SELECT sum(price) FROM sql"),
+    ):
+        with pytest.raises(credis.base.RedisReplyError):
+            conn.execute(command, "sql", statement)
+
+
+def test_publish(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "publish", "publish_view")
+    assert conn.execute("agg.new", "publish", "time", "price") == "OK"
+    conn.execute("agg.view", "publish", "publish_view", "interval", 60, "max", "price")
+    channel = b"__agg__:publish:publish_view"
+    with socket.create_connection(("127.0.0.1", redis_port), timeout=5) as sub:
+        sub.sendall(b"SUBSCRIBE " + channel + b"\r\n")
+        reader = sub.makefile("rb")
+        # *3 subscribe channel :1
+        for _ in range(6):
+            reader.readline()
+        conn.execute("agg.insert", "publish", "1564390729000", 2)
+        conn.execute("agg.insert", "publish", "1564390730000", 3)
+        conn.execute("agg.insert", "publish", "1564390741000", 1)
+        messages = []
+        while b"1564390680:[3.0]" not in messages:
+            # *3 message channel payload
+            lines = [reader.readline().rstrip(b"\r\n") for _ in range(7)]
+            assert lines[4] == channel
+            messages.append(lines[6])
+
+
+def test_save_every(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "save_every", "save_every_view", "nosave_view")
+    assert (
+        conn.execute("agg.new", "save_every", "time", "price", "saveevery", "100ms")
+        == "OK"
+    )
+    conn.execute(
+        "agg.view", "save_every", "save_every_view", "interval", 60, "max", "price"
+    )
+    conn.execute(
+        "agg.view",
+        "save_every",
+        "nosave_view",
+        "interval",
+        60,
+        "nosave",
+        "max",
+        "price",
+    )
+    # the options without an aggregation
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.view", "save_every", "empty_view", "nosave")
+    conn.execute("agg.insert", "save_every", "1564390729000", 2)
+    info = conn.execute("agg.info", "save_every")
+    info = dict(zip(info[::2], info[1::2]))
+    assert info["save_every"] == 100
+    views = [dict(zip(view[::2], view[1::2])) for view in info["views"]]
+    assert [view["nosave"] for view in views] == [0, 1]
+    time.sleep(0.5)
+    assert conn.execute("hget", "save_every_view", "1564390680") == "[2.0]"
+    assert conn.execute("exists", "nosave_view") == 0
+    conn.execute("agg.insert", "save_every", "1564390789000", 3)
+    assert conn.execute("hget", "nosave_view", "1564390680") == "[2.0]"
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.new", "save_every_zero", "time", "price", "saveevery", 0)
+
+
+def test_save_after_reload(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "reload_save", "reload_save_view")
+    assert (
+        conn.execute("agg.new", "reload_save", "time", "price", "saveevery", "100ms")
+        == "OK"
+    )
+    conn.execute(
+        "agg.view", "reload_save", "reload_save_view", "interval", 60, "max", "price"
+    )
+    conn.execute("agg.insert", "reload_save", "1564390729000", 2)
+    assert conn.execute("debug", "reload") == "OK"
+    conn.execute("agg.insert", "reload_save", "1564390730000", 3)
+    conn.execute("del", "reload_save_view")
+    time.sleep(1.5)
+    assert conn.execute("hget", "reload_save_view", "1564390680") == "[3.0]"
+
+
+def test_reload(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "reload", "reload_view", "reload_sum")
+    assert conn.execute("agg.new", "reload", "time", "price", "amount") == "OK"
+    conn.execute("agg.view", "reload", "reload_view", "interval", 60, "max", "price")
+    conn.execute("agg.insert", "reload", "1564390729000", 2, 1)
+    assert conn.execute("save") == "OK"
+    assert conn.execute("debug", "reload") == "OK"
+    assert conn.execute("agg.view", "reload", "reload_sum", "sum", "amount") == "OK"
+    conn.execute("agg.insert", "reload", "1564390730000", 3, 2)
+    _, high, _, _, total, _ = conn.execute("agg.current", "reload")
+    assert tuple(high) == ("3",)
+    assert tuple(total) == ("2",)
+
+
+def test_aof_rewrite(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "aof", "aof_view", "aof_min")
+    assert conn.execute("agg.new", "aof", "time", "price") == "OK"
+    conn.execute("agg.view", "aof", "aof_view", "interval", 60, "max", "price")
+    conn.execute("agg.view", "aof", "aof_min", "min", "price")
+    conn.execute("agg.insert", "aof", "1564390729000", 2)
+    conn.execute("agg.insert", "aof", "1564390730000-4", "-inf")
+    conn.execute("agg.insert", "aof", "1564390730000-5", 3)
+    conn.execute("config", "set", "aof-use-rdb-preamble", "no")
+    try:
+        conn.execute("bgrewriteaof")
+        while True:
+            info = conn.execute("info", "persistence")
+            rewriting = "aof_rewrite_in_progress:1" in info
+            if not rewriting and "aof_rewrite_scheduled:1" not in info:
+                break
+            time.sleep(0.1)
+        assert conn.execute("debug", "loadaof") == "OK"
+    finally:
+        conn.execute("config", "set", "aof-use-rdb-preamble", "yes")
+    assert conn.execute("agg.last_id", "aof") == "1564390730000-5"
+    _, values, bucket, _, min_values, _ = conn.execute("agg.current", "aof")
+    assert tuple(values) == ("3",)
+    assert bucket == "1564390680"
+    # the non-finite values are kept
+    assert tuple(min_values) == ("-inf",)
+    assert conn.execute("agg.insert", "aof", "1564390731000", 4) == "1564390731000-0"
+
+
+def test_corrupt_state(redis_port):
+    conn = credis.Connection(port=redis_port, decode_responses=True)
+    conn.execute("del", "corrupt", "corrupt_restore", "corrupt_view", "corrupt_bucket")
+    assert conn.execute("agg.new", "corrupt", "time", "price") == "OK"
+    conn.execute("agg.view", "corrupt", "corrupt_view", "max", "price")
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.import", "corrupt", '[["corrupt_view",null,[["max","x"]]]]')
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.restore", "corrupt_restore", '{"fields":["time"]}')
+    conn.execute(
+        "agg.view", "corrupt", "corrupt_bucket", "interval", 60, "max", "price"
+    )
+    exported = conn.execute("agg.export", "corrupt")
+    state = json.loads(exported)
+    op = state[0][2][0]
+    # the bucket time isn't a multiple of the interval
+    state[1][1] = 1564390681
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("agg.import", "corrupt", json.dumps(state))
+    assert conn.execute("agg.export", "corrupt") == exported
+    view = {"name": "corrupt_view", "fields": [[1, op, "max_price"]], "groupby": None}
+    rollup = dict(view, name="corrupt_rollup", **{"from": "corrupt_view"})
+    invalid = (
+        # field index out of range
+        {"views": [dict(view, fields=[[2, op, "max_price"]])]},
+        # row width doesn't match the columns
+        {"retain": 3600000, "rows": [[[1, 0], [1.0]]]},
+        # the source view of a rollup is after it
+        {"views": [rollup, view]},
+        {"views": [view, view]},
+        # the time column can't be consumed from the source stream
+        {"source": {"stream": "corrupt_stream", "fields": ["time"], "last_id": [0, 0]}},
+    )
+    for fields in invalid:
+        state = dict({"fields": ["time", "price"], "last_id": [0, 0]}, **fields)
+        with pytest.raises(credis.base.RedisReplyError):
+            conn.execute("agg.restore", "corrupt_restore", json.dumps(state))
+    assert conn.execute("exists", "corrupt_restore") == 0
+    # an unnamed rollup field is named after the source field it merges
+    fields = [[1, op, "high"], [1, op, "max_price"], [1, op, "peak"]]
+    view = dict(view, fields=fields)
+    rollup = dict(rollup, fields=[[2, op, ""]])
+    state = {"fields": ["time", "price"], "last_id": [0, 0], "views": [view, rollup]}
+    assert conn.execute("agg.restore", "corrupt_restore", json.dumps(state)) == "OK"
+    info = conn.execute("agg.info", "corrupt_restore")
+    _, rollup = dict(zip(info[::2], info[1::2]))["views"]
+    rollup = dict(zip(rollup[::2], rollup[1::2]))
+    assert tuple(map(tuple, rollup["fields"])) == (("peak", "max", "peak"),)
+    assert conn.execute("ping") == "PONG"
+
+
+def crc64(data):
+    """The crc-64-jones checksum in the footer of the dump payloads."""
+    crc = 0
+    for byte in data:
+        crc ^= byte
+        for _ in range(8):
+            crc = (crc >> 1) ^ (0x95AC9329AC4BC9B5 if crc & 1 else 0)
+    return crc
+
+
+def rdb_len(n):
+    if n < 1 << 6:
+        return bytes([n])
+    if n < 1 << 14:
+        return bytes([0x40 | n >> 8, n & 0xFF])
+    return b"\x80" + n.to_bytes(4, "big")
+
+
+def json_payload(payload, state):
+    """A dump payload of the table state in json, the older encoding."""
+    # the module id keeps the encoding version in the low 10 bits
+    module_id = int.from_bytes(payload[2:10], "big") & ~1023 | 1
+    state = json.dumps(state).encode()
+    body = payload[:2] + module_id.to_bytes(8, "big")
+    # a string, then the end of the module value
+    body += b"\x05" + rdb_len(len(state)) + state + b"\x00"
+    body += payload[-10:-8]
+    return body + crc64(body).to_bytes(8, "little")
+
+
+def test_corrupt_rdb(redis_port):
+    conn = credis.Connection(port=redis_port)
+    conn.execute("del", "corrupt_rdb", "corrupt_rdb_valid", "corrupt_rdb_invalid")
+    assert conn.execute("agg.new", "corrupt_rdb", "time", "price") == b"OK"
+    conn.execute("agg.view", "corrupt_rdb", "corrupt_rdb_view", "max", "price")
+    [[_, _, [op]]] = json.loads(conn.execute("agg.export", "corrupt_rdb"))
+    payload = conn.execute("dump", "corrupt_rdb")
+    view = {"name": "corrupt_rdb_view", "fields": [[1, op, "max_price"]]}
+    state = {"fields": ["time", "price"], "views": [view], "last_id": [0, 0]}
+    valid = json_payload(payload, state)
+    assert conn.execute("restore", "corrupt_rdb_valid", 0, valid) == b"OK"
+    tables = conn.execute("agg.list", "match", "corrupt_rdb_valid")
+    assert tuple(map(tuple, tables)) == ((b"corrupt_rdb_valid", 2, 1),)
+    # the field index is out of range, the load returns NULL and the restore fails
+    view["fields"] = [[2, op, "max_price"]]
+    invalid = json_payload(payload, state)
+    with pytest.raises(credis.base.RedisReplyError):
+        conn.execute("restore", "corrupt_rdb_invalid", 0, invalid)
+    assert conn.execute("exists", "corrupt_rdb_invalid") == 0
+    assert conn.execute("ping") == b"PONG"
+
+
 if __name__ == "__main__":
     test()
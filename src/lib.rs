@@ -1,35 +1,49 @@
-#[macro_use]
 extern crate redis_module;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
 extern crate serde_tuple;
 
-use std::collections::HashMap;
+use std::any::Any;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::convert::TryInto;
+use std::ffi::{CStr, CString};
 use std::mem;
 use std::num::{ParseIntError, TryFromIntError};
-use std::time::Duration;
+use std::ops::Deref;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::vec::Vec;
 
 use serde::de::Error;
-use serde::ser::SerializeSeq;
+use serde::ser::{SerializeMap, SerializeSeq};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json;
 
 use redis_module::{
-    parse_float, parse_integer, raw, Context, RedisError, RedisResult, RedisValue, REDIS_OK,
+    parse_float, parse_integer, raw, Context, LogLevel, RedisError, RedisResult, RedisString,
+    RedisValue, REDIS_OK,
 };
 
-use libc::c_int;
+use libc::{c_char, c_int};
 use redis_module::native_types::RedisType;
 use std::os::raw::c_void;
 
 type Time = f64;
 type Value = f64;
-const TIMER_INTERVAL: u64 = 1000;
+/// The default interval of the periodic save in milliseconds, the module load argument
+/// `SAVE_INTERVAL_MS` overrides it.
+static SAVE_INTERVAL: AtomicU64 = AtomicU64::new(1000);
+/// Keywords which start the table options in `agg.new`.
+const TABLE_OPTIONS: &[&str] = &["retain", "saveevery"];
+/// Number of stream entries read by each `xrange` call when consuming a source stream.
+const SOURCE_BATCH: usize = 1000;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 enum TimeFunc {
     Interval(u32),
 }
@@ -41,6 +55,31 @@ impl TimeFunc {
     }
 }
 
+/// Parse durations like `250ms`, `10s`, `5m`, `1h` or `7d` into milliseconds, the default unit is
+/// second.
+fn parse_duration(s: &str) -> Result<u64, RedisError> {
+    let s = s.to_lowercase();
+    let (n, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        None => (&s[..], 1000),
+        Some(i) => (
+            &s[..i],
+            match &s[i..] {
+                "ms" => 1,
+                "s" => 1000,
+                "m" => 60 * 1000,
+                "h" => 3600 * 1000,
+                "d" => 24 * 3600 * 1000,
+                _ => return Err(RedisError::Str("invalid duration")),
+            },
+        ),
+    };
+    let n = n
+        .parse::<u64>()
+        .map_err(|_| RedisError::Str("invalid duration"))?;
+    n.checked_mul(unit)
+        .ok_or(RedisError::Str("invalid duration"))
+}
+
 /// ```
 /// assert_eq!(StreamID{ms: 10, seq: 0} > StreamID{ms: 0, seq: 10});
 /// assert_eq!(StreamID{ms: 10, seq: 10} > StreamID{ms: 10, seq: 9});
@@ -71,6 +110,33 @@ impl StreamID {
             true
         }
     }
+
+    /// Parse `ms[-seq]`, the sequence number is optional.
+    fn parse(time: &str) -> Result<(u64, Option<u64>), RedisError> {
+        let parse_err = |e: ParseIntError| RedisError::String(e.to_string());
+        match time.find('-') {
+            None => Ok((time.parse::<u64>().map_err(parse_err)?, None)),
+            Some(i) => Ok((
+                time[..i].parse::<u64>().map_err(parse_err)?,
+                Some(time[i + 1..].parse::<u64>().map_err(parse_err)?),
+            )),
+        }
+    }
+
+    /// The smallest id which is bigger than `self`.
+    fn successor(&self) -> StreamID {
+        if self.seq == u64::MAX {
+            StreamID {
+                ms: self.ms + 1,
+                seq: 0,
+            }
+        } else {
+            StreamID {
+                ms: self.ms,
+                seq: self.seq + 1,
+            }
+        }
+    }
 }
 
 impl Into<String> for StreamID {
@@ -79,64 +145,401 @@ impl Into<String> for StreamID {
     }
 }
 
+/// A redis stream which feeds the table, new entries are consumed on keyspace notifications.
+#[derive(Serialize, Deserialize)]
+struct StreamSource {
+    stream: String,
+    /// the stream entry fields to ingest, mapped to the columns with the same names.
+    fields: Vec<String>,
+    /// the last consumed stream entry.
+    last_id: StreamID,
+}
+
+/// The encver of the typed encoding written by this version.
+const ENCVER: c_int = 3;
+
+/// The typed rdb encoding since encver 2, the options are prefixed with a flag, the sequences
+/// are prefixed with the length. Encver 3 adds the time of first and last.
+enum RdbWriter {
+    Io(*mut raw::RedisModuleIO),
+    /// the payload of `agg.restore` in the aof rewrite, the numbers are in little endian, the
+    /// strings are prefixed with the length.
+    Buffer(Vec<u8>),
+}
+
+impl RdbWriter {
+    fn unsigned(&mut self, value: u64) {
+        match self {
+            RdbWriter::Io(io) => unsafe { raw::RedisModule_SaveUnsigned.unwrap()(*io, value) },
+            RdbWriter::Buffer(buf) => buf.extend_from_slice(&value.to_le_bytes()),
+        }
+    }
+
+    fn double(&mut self, value: f64) {
+        match self {
+            RdbWriter::Io(io) => unsafe { raw::RedisModule_SaveDouble.unwrap()(*io, value) },
+            RdbWriter::Buffer(buf) => buf.extend_from_slice(&value.to_bits().to_le_bytes()),
+        }
+    }
+
+    fn string(&mut self, value: &str) {
+        match self {
+            RdbWriter::Io(io) => unsafe {
+                raw::RedisModule_SaveStringBuffer.unwrap()(
+                    *io,
+                    value.as_ptr() as *const c_char,
+                    value.len(),
+                )
+            },
+            RdbWriter::Buffer(buf) => {
+                buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+                buf.extend_from_slice(value.as_bytes());
+            }
+        }
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.unsigned(value as u64)
+    }
+
+    fn option_unsigned(&mut self, value: Option<u64>) {
+        self.bool(value.is_some());
+        if let Some(value) = value {
+            self.unsigned(value);
+        }
+    }
+
+    fn option_double(&mut self, value: Option<f64>) {
+        self.bool(value.is_some());
+        if let Some(value) = value {
+            self.double(value);
+        }
+    }
+
+    fn option_string(&mut self, value: Option<&str>) {
+        self.bool(value.is_some());
+        if let Some(value) = value {
+            self.string(value);
+        }
+    }
+
+    fn strings(&mut self, values: &[String]) {
+        self.unsigned(values.len() as u64);
+        for value in values {
+            self.string(value);
+        }
+    }
+
+    fn stream_id(&mut self, id: &StreamID) {
+        self.unsigned(id.ms);
+        self.unsigned(id.seq);
+    }
+}
+
+/// Where the typed encoding is read from.
+enum RdbInput {
+    Io(*mut raw::RedisModuleIO),
+    /// the payload of `agg.restore`, and the position in it.
+    Buffer(Vec<u8>, usize),
+}
+
+/// Reader of the typed rdb encoding, with the encver being loaded.
+struct RdbReader(RdbInput, c_int);
+
+impl RdbReader {
+    /// Fail if the io error is reported, only when the server supports it.
+    fn check(&self) -> Result<(), RedisError> {
+        let io = match self.0 {
+            RdbInput::Io(io) => io,
+            RdbInput::Buffer(..) => return Ok(()),
+        };
+        match unsafe { raw::RedisModule_IsIOError } {
+            Some(is_io_error) if unsafe { is_io_error(io) } != 0 => {
+                Err(RedisError::Str("rdb io error"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The next `n` bytes of the buffer.
+    fn take(&mut self, n: usize) -> Result<&[u8], RedisError> {
+        match self.0 {
+            RdbInput::Buffer(ref buf, ref mut pos) if buf.len() - *pos >= n => {
+                *pos += n;
+                Ok(&buf[*pos - n..*pos])
+            }
+            _ => Err(RedisError::Str("truncated payload")),
+        }
+    }
+
+    /// Whether the whole buffer is read.
+    fn is_end(&self) -> bool {
+        match self.0 {
+            RdbInput::Io(_) => true,
+            RdbInput::Buffer(ref buf, pos) => pos == buf.len(),
+        }
+    }
+
+    fn unsigned(&mut self) -> Result<u64, RedisError> {
+        let io = match self.0 {
+            RdbInput::Io(io) => io,
+            RdbInput::Buffer(..) => {
+                return Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+            }
+        };
+        let value = unsafe { raw::RedisModule_LoadUnsigned.unwrap()(io) };
+        self.check()?;
+        Ok(value)
+    }
+
+    fn double(&mut self) -> Result<f64, RedisError> {
+        let io = match self.0 {
+            RdbInput::Io(io) => io,
+            RdbInput::Buffer(..) => return Ok(f64::from_bits(self.unsigned()?)),
+        };
+        let value = unsafe { raw::RedisModule_LoadDouble.unwrap()(io) };
+        self.check()?;
+        Ok(value)
+    }
+
+    fn string(&mut self) -> Result<String, RedisError> {
+        let buf = match self.0 {
+            RdbInput::Io(io) => {
+                let mut len = 0;
+                let ptr = unsafe { raw::RedisModule_LoadStringBuffer.unwrap()(io, &mut len) };
+                self.check()?;
+                if ptr.is_null() {
+                    return Err(RedisError::Str("rdb io error"));
+                }
+                let buf = unsafe { slice::from_raw_parts(ptr as *const u8, len) }.to_vec();
+                unsafe { raw::RedisModule_Free.unwrap()(ptr as *mut c_void) };
+                buf
+            }
+            RdbInput::Buffer(..) => {
+                let len = self.len()?;
+                self.take(len)?.to_vec()
+            }
+        };
+        String::from_utf8(buf).map_err(|err| RedisError::String(err.to_string()))
+    }
+
+    fn bool(&mut self) -> Result<bool, RedisError> {
+        Ok(self.unsigned()? != 0)
+    }
+
+    fn option_unsigned(&mut self) -> Result<Option<u64>, RedisError> {
+        Ok(if self.bool()? {
+            Some(self.unsigned()?)
+        } else {
+            None
+        })
+    }
+
+    fn option_double(&mut self) -> Result<Option<f64>, RedisError> {
+        Ok(if self.bool()? {
+            Some(self.double()?)
+        } else {
+            None
+        })
+    }
+
+    /// The time of first and last, which is saved since encver 3.
+    fn option_time(&mut self) -> Result<Option<Time>, RedisError> {
+        if self.1 < 3 {
+            return Ok(None);
+        }
+        self.option_double()
+    }
+
+    fn option_string(&mut self) -> Result<Option<String>, RedisError> {
+        Ok(if self.bool()? {
+            Some(self.string()?)
+        } else {
+            None
+        })
+    }
+
+    fn len(&mut self) -> Result<usize, RedisError> {
+        let len = self.unsigned()?;
+        len.try_into()
+            .map_err(|err: TryFromIntError| RedisError::String(err.to_string()))
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>, RedisError> {
+        (0..self.len()?).map(|_| self.string()).collect()
+    }
+
+    fn stream_id(&mut self) -> Result<StreamID, RedisError> {
+        Ok(StreamID {
+            ms: self.unsigned()?,
+            seq: self.unsigned()?,
+        })
+    }
+}
+
 trait AggOp {
-    fn save(&self) -> (&str, String);
-    fn load(&mut self, buf: &str);
+    /// The name of the aggregate operation, like `max`.
+    fn name(&self) -> &'static str;
+    /// The partial state in json.
+    fn save(&self) -> String;
+    fn load(&mut self, buf: &str) -> serde_json::Result<()>;
+    /// Write the partial state in the typed rdb encoding.
+    fn save_rdb(&self, rdb: &mut RdbWriter);
+    fn load_rdb(&mut self, rdb: &mut RdbReader) -> Result<(), RedisError>;
     fn update(&mut self, value: Value);
+    /// Update with the value of a row at `time`, first and last keep the time to be merged.
+    fn update_at(&mut self, _time: Time, value: Value) {
+        self.update(value)
+    }
+    /// Merge the partial state of another op of the same type, which covers later rows.
+    fn merge(&mut self, other: &dyn AggOp);
     fn reset(&mut self);
     fn current(&self) -> Option<Value>;
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// Load the value and the time of first and last, the states saved before the time is kept are
+/// the bare value.
+fn load_timed(buf: &str) -> serde_json::Result<(Option<Value>, Option<Time>)> {
+    serde_json::from_str(buf).or_else(|_| Ok((serde_json::from_str(buf)?, None)))
 }
 
+/// Whether the time of the other state is before, the states without the time are in the order
+/// of the merge.
+fn is_before(time: Option<Time>, other: Option<Time>) -> bool {
+    match (time, other) {
+        (Some(time), Some(other)) => other < time,
+        _ => false,
+    }
+}
+
+/// The first value and the time of its row.
 #[derive(Default)]
-struct AggFirst(Option<Value>);
+struct AggFirst(Option<Value>, Option<Time>);
 impl AggOp for AggFirst {
-    fn save(&self) -> (&str, String) {
-        ("first", serde_json::to_string(&self.0).unwrap())
+    fn name(&self) -> &'static str {
+        "first"
+    }
+    fn save(&self) -> String {
+        serde_json::to_string(&(self.0, self.1)).unwrap()
     }
-    fn load(&mut self, buf: &str) {
-        self.0 = serde_json::from_str::<Option<Value>>(buf).unwrap();
+    fn load(&mut self, buf: &str) -> serde_json::Result<()> {
+        let (value, time) = load_timed(buf)?;
+        self.0 = value;
+        self.1 = time;
+        Ok(())
+    }
+    fn save_rdb(&self, rdb: &mut RdbWriter) {
+        rdb.option_double(self.0);
+        rdb.option_double(self.1);
+    }
+    fn load_rdb(&mut self, rdb: &mut RdbReader) -> Result<(), RedisError> {
+        self.0 = rdb.option_double()?;
+        self.1 = rdb.option_time()?;
+        Ok(())
     }
     fn update(&mut self, value: Value) {
         if let None = self.0 {
             self.0 = Some(value)
         }
     }
+    fn update_at(&mut self, time: Time, value: Value) {
+        if self.0.is_none() {
+            self.0 = Some(value);
+            self.1 = Some(time);
+        }
+    }
+    fn merge(&mut self, other: &dyn AggOp) {
+        if let Some(other) = other.as_any().downcast_ref::<Self>() {
+            if other.0.is_some() && (self.0.is_none() || is_before(self.1, other.1)) {
+                self.0 = other.0;
+                self.1 = other.1;
+            }
+        }
+    }
     fn reset(&mut self) {
         self.0 = None;
+        self.1 = None;
     }
     fn current(&self) -> Option<Value> {
         return self.0;
     }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
+/// The last value and the time of its row.
 #[derive(Default)]
-struct AggLast(Option<Value>);
+struct AggLast(Option<Value>, Option<Time>);
 impl AggOp for AggLast {
-    fn save(&self) -> (&str, String) {
-        ("last", serde_json::to_string(&self.0).unwrap())
+    fn name(&self) -> &'static str {
+        "last"
+    }
+    fn save(&self) -> String {
+        serde_json::to_string(&(self.0, self.1)).unwrap()
+    }
+    fn load(&mut self, buf: &str) -> serde_json::Result<()> {
+        let (value, time) = load_timed(buf)?;
+        self.0 = value;
+        self.1 = time;
+        Ok(())
+    }
+    fn save_rdb(&self, rdb: &mut RdbWriter) {
+        rdb.option_double(self.0);
+        rdb.option_double(self.1);
     }
-    fn load(&mut self, buf: &str) {
-        self.0 = serde_json::from_str::<Option<Value>>(buf).unwrap();
+    fn load_rdb(&mut self, rdb: &mut RdbReader) -> Result<(), RedisError> {
+        self.0 = rdb.option_double()?;
+        self.1 = rdb.option_time()?;
+        Ok(())
     }
     fn update(&mut self, value: Value) {
         self.0 = Some(value)
     }
+    fn update_at(&mut self, time: Time, value: Value) {
+        self.0 = Some(value);
+        self.1 = Some(time);
+    }
+    fn merge(&mut self, other: &dyn AggOp) {
+        if let Some(other) = other.as_any().downcast_ref::<Self>() {
+            if other.0.is_some() && !is_before(self.1, other.1) {
+                self.0 = other.0;
+                self.1 = other.1;
+            }
+        }
+    }
     fn reset(&mut self) {
         self.0 = None;
+        self.1 = None;
     }
     fn current(&self) -> Option<Value> {
         return self.0;
     }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 #[derive(Default)]
 struct AggMin(Option<Value>);
 impl AggOp for AggMin {
-    fn save(&self) -> (&str, String) {
-        ("min", serde_json::to_string(&self.0).unwrap())
+    fn name(&self) -> &'static str {
+        "min"
     }
-    fn load(&mut self, buf: &str) {
-        self.0 = serde_json::from_str::<Option<Value>>(buf).unwrap();
+    fn save(&self) -> String {
+        serde_json::to_string(&self.0).unwrap()
+    }
+    fn load(&mut self, buf: &str) -> serde_json::Result<()> {
+        self.0 = serde_json::from_str::<Option<Value>>(buf)?;
+        Ok(())
+    }
+    fn save_rdb(&self, rdb: &mut RdbWriter) {
+        rdb.option_double(self.0);
+    }
+    fn load_rdb(&mut self, rdb: &mut RdbReader) -> Result<(), RedisError> {
+        self.0 = rdb.option_double()?;
+        Ok(())
     }
     fn update(&mut self, value: Value) {
         match self.0 {
@@ -145,22 +548,43 @@ impl AggOp for AggMin {
             _ => {}
         }
     }
+    fn merge(&mut self, other: &dyn AggOp) {
+        if let Some(other) = other.as_any().downcast_ref::<Self>() {
+            if let Some(value) = other.0 {
+                self.update(value)
+            }
+        }
+    }
     fn reset(&mut self) {
         self.0 = None;
     }
     fn current(&self) -> Option<Value> {
         return self.0;
     }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 #[derive(Default)]
 struct AggMax(Option<Value>);
 impl AggOp for AggMax {
-    fn save(&self) -> (&str, String) {
-        ("max", serde_json::to_string(&self.0).unwrap())
+    fn name(&self) -> &'static str {
+        "max"
+    }
+    fn save(&self) -> String {
+        serde_json::to_string(&self.0).unwrap()
+    }
+    fn load(&mut self, buf: &str) -> serde_json::Result<()> {
+        self.0 = serde_json::from_str::<Option<Value>>(buf)?;
+        Ok(())
     }
-    fn load(&mut self, buf: &str) {
-        self.0 = serde_json::from_str::<Option<Value>>(buf).unwrap();
+    fn save_rdb(&self, rdb: &mut RdbWriter) {
+        rdb.option_double(self.0);
+    }
+    fn load_rdb(&mut self, rdb: &mut RdbReader) -> Result<(), RedisError> {
+        self.0 = rdb.option_double()?;
+        Ok(())
     }
     fn update(&mut self, value: Value) {
         match self.0 {
@@ -169,12 +593,22 @@ impl AggOp for AggMax {
             _ => {}
         }
     }
+    fn merge(&mut self, other: &dyn AggOp) {
+        if let Some(other) = other.as_any().downcast_ref::<Self>() {
+            if let Some(value) = other.0 {
+                self.update(value)
+            }
+        }
+    }
     fn reset(&mut self) {
         self.0 = None;
     }
     fn current(&self) -> Option<Value> {
         return self.0;
     }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 #[derive(Default)]
@@ -183,21 +617,37 @@ struct AggAvg {
     sum: Value,
 }
 impl AggOp for AggAvg {
-    fn save(&self) -> (&str, String) {
-        (
-            "avg",
-            serde_json::to_string(&(self.count, self.sum)).unwrap(),
-        )
+    fn name(&self) -> &'static str {
+        "avg"
+    }
+    fn save(&self) -> String {
+        serde_json::to_string(&(self.count, self.sum)).unwrap()
     }
-    fn load(&mut self, buf: &str) {
-        let t = serde_json::from_str::<(usize, Value)>(buf).unwrap();
+    fn load(&mut self, buf: &str) -> serde_json::Result<()> {
+        let t = serde_json::from_str::<(usize, Value)>(buf)?;
         self.count = t.0;
         self.sum = t.1;
+        Ok(())
+    }
+    fn save_rdb(&self, rdb: &mut RdbWriter) {
+        rdb.unsigned(self.count as u64);
+        rdb.double(self.sum);
+    }
+    fn load_rdb(&mut self, rdb: &mut RdbReader) -> Result<(), RedisError> {
+        self.count = rdb.unsigned()? as usize;
+        self.sum = rdb.double()?;
+        Ok(())
     }
     fn update(&mut self, value: Value) {
         self.sum += value;
         self.count += 1;
     }
+    fn merge(&mut self, other: &dyn AggOp) {
+        if let Some(other) = other.as_any().downcast_ref::<Self>() {
+            self.sum += other.sum;
+            self.count += other.count;
+        }
+    }
     fn reset(&mut self) {
         self.count = 0;
         self.sum = 0.;
@@ -209,46 +659,87 @@ impl AggOp for AggAvg {
             return Some(self.sum / self.count as f64);
         }
     }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 #[derive(Default)]
 struct AggSum(Value);
 impl AggOp for AggSum {
-    fn save(&self) -> (&str, String) {
-        ("sum", serde_json::to_string(&self.0).unwrap())
+    fn name(&self) -> &'static str {
+        "sum"
+    }
+    fn save(&self) -> String {
+        serde_json::to_string(&self.0).unwrap()
     }
-    fn load(&mut self, buf: &str) {
-        self.0 = serde_json::from_str(buf).unwrap();
+    fn load(&mut self, buf: &str) -> serde_json::Result<()> {
+        self.0 = serde_json::from_str(buf)?;
+        Ok(())
+    }
+    fn save_rdb(&self, rdb: &mut RdbWriter) {
+        rdb.double(self.0);
+    }
+    fn load_rdb(&mut self, rdb: &mut RdbReader) -> Result<(), RedisError> {
+        self.0 = rdb.double()?;
+        Ok(())
     }
     fn update(&mut self, value: Value) {
         self.0 += value;
     }
+    fn merge(&mut self, other: &dyn AggOp) {
+        if let Some(other) = other.as_any().downcast_ref::<Self>() {
+            self.0 += other.0;
+        }
+    }
     fn reset(&mut self) {
         self.0 = 0.;
     }
     fn current(&self) -> Option<Value> {
         return Some(self.0);
     }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 #[derive(Default)]
 struct AggCount(usize);
 impl AggOp for AggCount {
-    fn save(&self) -> (&str, String) {
-        ("count", serde_json::to_string(&self.0).unwrap())
+    fn name(&self) -> &'static str {
+        "count"
+    }
+    fn save(&self) -> String {
+        serde_json::to_string(&self.0).unwrap()
     }
-    fn load(&mut self, buf: &str) {
-        self.0 = serde_json::from_str(buf).unwrap();
+    fn load(&mut self, buf: &str) -> serde_json::Result<()> {
+        self.0 = serde_json::from_str(buf)?;
+        Ok(())
+    }
+    fn save_rdb(&self, rdb: &mut RdbWriter) {
+        rdb.unsigned(self.0 as u64);
+    }
+    fn load_rdb(&mut self, rdb: &mut RdbReader) -> Result<(), RedisError> {
+        self.0 = rdb.unsigned()? as usize;
+        Ok(())
     }
     fn update(&mut self, _value: Value) {
         self.0 += 1;
     }
+    fn merge(&mut self, other: &dyn AggOp) {
+        if let Some(other) = other.as_any().downcast_ref::<Self>() {
+            self.0 += other.0;
+        }
+    }
     fn reset(&mut self) {
         self.0 = 0;
     }
     fn current(&self) -> Option<Value> {
         return Some(self.0 as Value);
     }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 #[derive(Default)]
@@ -262,19 +753,35 @@ impl AggStd {
     fn to_string(&self) -> String {
         serde_json::to_string(&(self.sum, self.sum_2, self.count)).unwrap()
     }
-    fn from_str(buf: &str) -> AggStd {
-        let t = serde_json::from_str::<(Value, Value, usize)>(buf).unwrap();
-        return Self {
+    fn from_str(buf: &str) -> serde_json::Result<AggStd> {
+        let t = serde_json::from_str::<(Value, Value, usize)>(buf)?;
+        return Ok(Self {
             sum: t.0,
             sum_2: t.1,
             count: t.2,
-        };
+        });
+    }
+    fn save_rdb(&self, rdb: &mut RdbWriter) {
+        rdb.double(self.sum);
+        rdb.double(self.sum_2);
+        rdb.unsigned(self.count as u64);
+    }
+    fn load_rdb(&mut self, rdb: &mut RdbReader) -> Result<(), RedisError> {
+        self.sum = rdb.double()?;
+        self.sum_2 = rdb.double()?;
+        self.count = rdb.unsigned()? as usize;
+        Ok(())
     }
     fn add(&mut self, value: Value) {
         self.sum += value;
         self.sum_2 += value * value;
         self.count += 1;
     }
+    fn merge(&mut self, other: &AggStd) {
+        self.sum += other.sum;
+        self.sum_2 += other.sum_2;
+        self.count += other.count;
+    }
     fn reset(&mut self) {
         self.sum = 0.;
         self.sum_2 = 0.;
@@ -296,15 +803,30 @@ impl AggStd {
 #[derive(Default)]
 struct AggVarP(AggStd);
 impl AggOp for AggVarP {
-    fn save(&self) -> (&str, String) {
-        ("varp", self.0.to_string())
+    fn name(&self) -> &'static str {
+        "varp"
+    }
+    fn save(&self) -> String {
+        self.0.to_string()
+    }
+    fn load(&mut self, buf: &str) -> serde_json::Result<()> {
+        self.0 = AggStd::from_str(buf)?;
+        Ok(())
     }
-    fn load(&mut self, buf: &str) {
-        self.0 = AggStd::from_str(buf);
+    fn save_rdb(&self, rdb: &mut RdbWriter) {
+        self.0.save_rdb(rdb);
+    }
+    fn load_rdb(&mut self, rdb: &mut RdbReader) -> Result<(), RedisError> {
+        self.0.load_rdb(rdb)
     }
     fn update(&mut self, value: Value) {
         self.0.add(value)
     }
+    fn merge(&mut self, other: &dyn AggOp) {
+        if let Some(other) = other.as_any().downcast_ref::<Self>() {
+            self.0.merge(&other.0)
+        }
+    }
     fn reset(&mut self) {
         self.0.reset()
     }
@@ -315,20 +837,38 @@ impl AggOp for AggVarP {
             Some(self.0.variance() / self.0.count as Value)
         }
     }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 #[derive(Default)]
 struct AggVarS(AggStd);
 impl AggOp for AggVarS {
-    fn save(&self) -> (&str, String) {
-        ("vars", self.0.to_string())
+    fn name(&self) -> &'static str {
+        "vars"
     }
-    fn load(&mut self, buf: &str) {
-        self.0 = AggStd::from_str(buf);
+    fn save(&self) -> String {
+        self.0.to_string()
+    }
+    fn load(&mut self, buf: &str) -> serde_json::Result<()> {
+        self.0 = AggStd::from_str(buf)?;
+        Ok(())
+    }
+    fn save_rdb(&self, rdb: &mut RdbWriter) {
+        self.0.save_rdb(rdb);
+    }
+    fn load_rdb(&mut self, rdb: &mut RdbReader) -> Result<(), RedisError> {
+        self.0.load_rdb(rdb)
     }
     fn update(&mut self, value: Value) {
         self.0.add(value)
     }
+    fn merge(&mut self, other: &dyn AggOp) {
+        if let Some(other) = other.as_any().downcast_ref::<Self>() {
+            self.0.merge(&other.0)
+        }
+    }
     fn reset(&mut self) {
         self.0.reset()
     }
@@ -341,20 +881,38 @@ impl AggOp for AggVarS {
             Some(self.0.variance() / (self.0.count - 1) as Value)
         }
     }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 #[derive(Default)]
 struct AggStdP(AggStd);
 impl AggOp for AggStdP {
-    fn save(&self) -> (&str, String) {
-        ("stdp", self.0.to_string())
+    fn name(&self) -> &'static str {
+        "stdp"
+    }
+    fn save(&self) -> String {
+        self.0.to_string()
     }
-    fn load(&mut self, buf: &str) {
-        self.0 = AggStd::from_str(buf);
+    fn load(&mut self, buf: &str) -> serde_json::Result<()> {
+        self.0 = AggStd::from_str(buf)?;
+        Ok(())
+    }
+    fn save_rdb(&self, rdb: &mut RdbWriter) {
+        self.0.save_rdb(rdb);
+    }
+    fn load_rdb(&mut self, rdb: &mut RdbReader) -> Result<(), RedisError> {
+        self.0.load_rdb(rdb)
     }
     fn update(&mut self, value: Value) {
         self.0.add(value)
     }
+    fn merge(&mut self, other: &dyn AggOp) {
+        if let Some(other) = other.as_any().downcast_ref::<Self>() {
+            self.0.merge(&other.0)
+        }
+    }
     fn reset(&mut self) {
         self.0.reset()
     }
@@ -365,20 +923,38 @@ impl AggOp for AggStdP {
             Some((self.0.variance() / self.0.count as Value).sqrt())
         }
     }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 #[derive(Default)]
 struct AggStdS(AggStd);
 impl AggOp for AggStdS {
-    fn save(&self) -> (&str, String) {
-        ("stds", self.0.to_string())
+    fn name(&self) -> &'static str {
+        "stds"
+    }
+    fn save(&self) -> String {
+        self.0.to_string()
+    }
+    fn load(&mut self, buf: &str) -> serde_json::Result<()> {
+        self.0 = AggStd::from_str(buf)?;
+        Ok(())
+    }
+    fn save_rdb(&self, rdb: &mut RdbWriter) {
+        self.0.save_rdb(rdb);
     }
-    fn load(&mut self, buf: &str) {
-        self.0 = AggStd::from_str(buf);
+    fn load_rdb(&mut self, rdb: &mut RdbReader) -> Result<(), RedisError> {
+        self.0.load_rdb(rdb)
     }
     fn update(&mut self, value: Value) {
         self.0.add(value)
     }
+    fn merge(&mut self, other: &dyn AggOp) {
+        if let Some(other) = other.as_any().downcast_ref::<Self>() {
+            self.0.merge(&other.0)
+        }
+    }
     fn reset(&mut self) {
         self.0.reset()
     }
@@ -391,6 +967,9 @@ impl AggOp for AggStdS {
             Some((self.0.variance() / (self.0.count - 1) as Value).sqrt())
         }
     }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 fn parse_agg_type(name: &str) -> Option<Box<dyn AggOp>> {
@@ -415,7 +994,7 @@ impl Serialize for Box<dyn AggOp> {
     where
         S: Serializer,
     {
-        let (name, value) = self.save();
+        let (name, value) = (self.name(), self.save());
         let mut seq = serializer.serialize_seq(Some(2))?;
         seq.serialize_element(name)?;
         seq.serialize_element(&value)?;
@@ -430,12 +1009,12 @@ impl<'de> Deserialize<'de> for Box<dyn AggOp> {
     {
         let (name, value) = Deserialize::deserialize(deserializer)?;
         let mut agg = parse_agg_type(name).ok_or(Error::custom("invalid agg type"))?;
-        agg.load(value);
+        agg.load(value).map_err(Error::custom)?;
         Ok(agg)
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct GroupState {
     current: Time,
     func: TimeFunc,
@@ -443,8 +1022,67 @@ struct GroupState {
 
 #[derive(Serialize_tuple, Deserialize_tuple)]
 pub struct AggField {
+    /// index of the column, or index of the source view field for rollup views.
     index: usize,
     op: Box<dyn AggOp>,
+    /// label of the aggregation in the named outputs, like `max_price`.
+    #[serde(default)]
+    name: String,
+}
+
+/// Emit each finalized bucket into a redis stream.
+#[derive(Serialize, Deserialize, Clone)]
+struct StreamOutput {
+    stream: String,
+    maxlen: Option<u64>,
+}
+
+/// Where the results of group by aggregation are saved.
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+enum Output {
+    /// hash keyed by the bucket time.
+    Hash,
+    /// sorted set scored by the bucket time, the members are prefixed with the bucket time.
+    Zset,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output::Hash
+    }
+}
+
+/// How the results of a bucket are encoded.
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+enum Format {
+    /// json array in the order of the fields, like `[1001.11,1.0]`.
+    Array,
+    /// json object keyed by the field names, like `{"open":1001.11,"amount":1.0}`.
+    Object,
+    /// a redis hash per bucket, with a hash field per aggregation.
+    Hash,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Array
+    }
+}
+
+/// Serialize the current results as a map keyed by the field names, keeps the field order.
+struct NamedValues<'a>(&'a [AggField]);
+
+impl<'a> Serialize for NamedValues<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for agg in self.0 {
+            map.serialize_entry(&agg.name, &agg.op.current())?;
+        }
+        map.end()
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -452,110 +1090,1329 @@ pub struct AggView {
     name: String,
     fields: Vec<AggField>,
     groupby: Option<GroupState>,
+    #[serde(default)]
+    stream: Option<StreamOutput>,
+    #[serde(default)]
+    output: Output,
+    #[serde(default)]
+    format: Format,
+    /// buckets older than the retention (in milliseconds) are trimmed from the outputs.
+    #[serde(default)]
+    retention: Option<u64>,
+    /// only keep the latest buckets in the outputs.
+    #[serde(default)]
+    maxbuckets: Option<u64>,
+    /// the source view of a rollup view, which merges the finalized buckets of the source view
+    /// instead of the rows.
+    #[serde(default)]
+    from: Option<String>,
+    /// hash keyed by the bucket time, where the partial states of the finalized buckets are saved
+    /// for `agg.query`.
+    #[serde(default)]
+    states: Option<String>,
+    /// skipped by the periodic save, only saves on rollover and `agg.save`.
+    #[serde(default)]
+    nosave: bool,
+    #[serde(default)]
+    stats: ViewStats,
+}
+
+/// Counters of a view, reported by `agg.info`.
+#[derive(Serialize, Deserialize, Default)]
+struct ViewStats {
+    /// rows (or source buckets for rollup views) aggregated.
+    rows: u64,
+    /// rows dropped because they belong to a finalized bucket.
+    late: u64,
+    /// unix time of the last save in milliseconds, it's wall clock time of the instance, so it's
+    /// not persisted.
+    #[serde(skip)]
+    last_save: u64,
+}
+
+/// The input of a view, either a row or the finalized bucket of another view.
+enum Input<'a> {
+    Row(&'a [Option<Value>]),
+    Merge(usize),
 }
 
+/// The exported partial state of a view: name, current bucket and the op states.
+type ViewState<T> = (String, Option<Time>, Vec<T>);
+
 impl AggView {
-    pub fn update(&mut self, ctx: &Context, values: &[Value]) -> Result<(), RedisError> {
+    fn interval(&self) -> Option<u32> {
         match self.groupby {
-            None => {}
-            Some(ref groupby) => {
-                let grouptime = groupby.func.apply(values[0]);
-                if grouptime > groupby.current {
-                    // save current and reset
-                    if groupby.current > 0. {
-                        self.save(ctx)?;
-                    }
-                    for agg in &mut self.fields {
-                        agg.op.reset()
-                    }
-                    self.groupby.as_mut().unwrap().current = grouptime;
-                } else if grouptime < groupby.current {
-                    // ignore the item
-                    return Ok(());
-                }
-            }
+            Some(GroupState {
+                func: TimeFunc::Interval(n),
+                ..
+            }) => Some(n),
+            None => None,
+        }
+    }
+
+    fn save_rdb(&self, rdb: &mut RdbWriter) {
+        rdb.string(&self.name);
+        rdb.unsigned(self.fields.len() as u64);
+        for agg in &self.fields {
+            rdb.unsigned(agg.index as u64);
+            rdb.string(agg.op.name());
+            agg.op.save_rdb(rdb);
+            rdb.string(&agg.name);
+        }
+        rdb.bool(self.groupby.is_some());
+        if let Some(ref groupby) = self.groupby {
+            rdb.double(groupby.current);
+            match groupby.func {
+                TimeFunc::Interval(n) => rdb.unsigned(n as u64),
+            }
+        }
+        rdb.bool(self.stream.is_some());
+        if let Some(ref output) = self.stream {
+            rdb.string(&output.stream);
+            rdb.option_unsigned(output.maxlen);
+        }
+        rdb.unsigned(match self.output {
+            Output::Hash => 0,
+            Output::Zset => 1,
+        });
+        rdb.unsigned(match self.format {
+            Format::Array => 0,
+            Format::Object => 1,
+            Format::Hash => 2,
+        });
+        rdb.option_unsigned(self.retention);
+        rdb.option_unsigned(self.maxbuckets);
+        rdb.option_string(self.from.as_deref());
+        rdb.option_string(self.states.as_deref());
+        rdb.bool(self.nosave);
+        rdb.unsigned(self.stats.rows);
+        rdb.unsigned(self.stats.late);
+    }
+
+    fn load_rdb(rdb: &mut RdbReader) -> Result<AggView, RedisError> {
+        let name = rdb.string()?;
+        let mut fields = Vec::new();
+        for _ in 0..rdb.len()? {
+            let index = rdb.len()?;
+            let mut op = parse_agg_type(&rdb.string()?)
+                .ok_or(RedisError::Str("invalid aggregate operation"))?;
+            op.load_rdb(rdb)?;
+            fields.push(AggField {
+                index,
+                op,
+                name: rdb.string()?,
+            });
+        }
+        let groupby = if rdb.bool()? {
+            let current = rdb.double()?;
+            let n = rdb
+                .unsigned()?
+                .try_into()
+                .map_err(|err: TryFromIntError| RedisError::String(err.to_string()))?;
+            Some(GroupState {
+                current,
+                func: TimeFunc::Interval(n),
+            })
+        } else {
+            None
+        };
+        let stream = if rdb.bool()? {
+            Some(StreamOutput {
+                stream: rdb.string()?,
+                maxlen: rdb.option_unsigned()?,
+            })
+        } else {
+            None
+        };
+        let output = match rdb.unsigned()? {
+            0 => Output::Hash,
+            1 => Output::Zset,
+            _ => return Err(RedisError::Str("invalid output type")),
+        };
+        let format = match rdb.unsigned()? {
+            0 => Format::Array,
+            1 => Format::Object,
+            2 => Format::Hash,
+            _ => return Err(RedisError::Str("invalid format")),
+        };
+        Ok(AggView {
+            name,
+            fields,
+            groupby,
+            stream,
+            output,
+            format,
+            retention: rdb.option_unsigned()?,
+            maxbuckets: rdb.option_unsigned()?,
+            from: rdb.option_string()?,
+            states: rdb.option_string()?,
+            nosave: rdb.bool()?,
+            stats: ViewStats {
+                rows: rdb.unsigned()?,
+                late: rdb.unsigned()?,
+                last_save: 0,
+            },
+        })
+    }
+
+    /// Whether the view has the same definition with another one, so the states are mergeable,
+    /// the names don't matter since they are the output keys.
+    fn same_schema(&self, other: &AggView) -> bool {
+        self.from.is_some() == other.from.is_some()
+            && self.interval() == other.interval()
+            && self.fields.len() == other.fields.len()
+            && self
+                .fields
+                .iter()
+                .zip(&other.fields)
+                .all(|(a, b)| a.index == b.index && a.op.name() == b.op.name())
+    }
+
+    /// A copy of the view definition with empty states.
+    fn fresh(&self) -> AggView {
+        AggView {
+            name: self.name.clone(),
+            fields: self
+                .fields
+                .iter()
+                .map(|agg| AggField {
+                    index: agg.index,
+                    op: parse_agg_type(agg.op.name()).unwrap(),
+                    name: agg.name.clone(),
+                })
+                .collect(),
+            groupby: self.groupby.as_ref().map(|g| GroupState {
+                current: 0.,
+                func: g.func.clone(),
+            }),
+            stream: self.stream.clone(),
+            output: self.output.clone(),
+            format: self.format.clone(),
+            retention: self.retention,
+            maxbuckets: self.maxbuckets,
+            from: self.from.clone(),
+            states: self.states.clone(),
+            nosave: self.nosave,
+            stats: ViewStats::default(),
+        }
+    }
+
+    /// Whether the time starts a new bucket and finalizes the current one.
+    fn is_rollover(&self, time: Time) -> bool {
+        match self.groupby {
+            None => false,
+            Some(ref groupby) => groupby.current > 0. && groupby.func.apply(time) > groupby.current,
+        }
+    }
+
+    /// Move to the bucket of the time, returns false if the time belongs to a past bucket.
+    fn advance(&mut self, ctx: &ModuleContext, time: Time) -> Result<bool, RedisError> {
+        match self.groupby {
+            None => {}
+            Some(ref groupby) => {
+                let grouptime = groupby.func.apply(time);
+                if grouptime > groupby.current {
+                    // save current and reset
+                    let previous = groupby.current;
+                    if previous > 0. {
+                        self.save(ctx)?;
+                        self.emit(ctx);
+                    }
+                    for agg in &mut self.fields {
+                        agg.op.reset()
+                    }
+                    self.groupby.as_mut().unwrap().current = grouptime;
+                    self.trim(ctx, previous)?;
+                } else if grouptime < groupby.current {
+                    // ignore the item
+                    self.stats.late += 1;
+                    return Ok(false);
+                }
+            }
+        }
+        self.stats.rows += 1;
+        Ok(true)
+    }
+
+    pub fn update(
+        &mut self,
+        ctx: &ModuleContext,
+        time: Time,
+        values: &[Option<Value>],
+    ) -> Result<(), RedisError> {
+        if !self.advance(ctx, time)? {
+            return Ok(());
+        }
+        for agg in &mut self.fields {
+            // null values are skipped, like sql aggregate functions do
+            if let Some(value) = values[agg.index] {
+                agg.op.update_at(time, value)
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge a finalized bucket of the source view into a rollup view.
+    fn merge(
+        &mut self,
+        ctx: &ModuleContext,
+        time: Time,
+        source: &AggView,
+    ) -> Result<(), RedisError> {
+        if !self.advance(ctx, time)? {
+            return Ok(());
         }
         for agg in &mut self.fields {
-            agg.op.update(values[agg.index])
+            agg.op.merge(source.fields[agg.index].op.as_ref());
         }
         Ok(())
     }
 
     pub fn encode(&self) -> Result<String, RedisError> {
-        let mut values = Vec::new();
-        values.reserve_exact(self.fields.len());
+        let result = if self.format == Format::Array {
+            let mut values = Vec::new();
+            values.reserve_exact(self.fields.len());
+            for agg in &self.fields {
+                values.push(agg.op.current());
+            }
+            serde_json::to_string(&values)
+        } else {
+            serde_json::to_string(&NamedValues(&self.fields))
+        };
+        result.map_err(|err| RedisError::String(format!("encode failed: {}", err)))
+    }
+
+    /// Trim the old buckets from the outputs according to `retention` and `maxbuckets`, after
+    /// rolling over from the `previous` bucket.
+    fn trim(&self, ctx: &ModuleContext, previous: Time) -> Result<(), RedisError> {
+        let groupby = match self.groupby {
+            Some(ref groupby) if self.retention.is_some() || self.maxbuckets.is_some() => groupby,
+            _ => return Ok(()),
+        };
+        // buckets not after the cutoff are expired
+        let cutoff = self
+            .retention
+            .map(|ms| groupby.current - ms as Time / 1000.);
+        if let Some(ref states) = self.states {
+            self.trim_hash(ctx, states, previous)?;
+        }
+        match self.output {
+            Output::Hash => self.trim_hash(ctx, &self.name, previous)?,
+            Output::Zset => {
+                if let Some(cutoff) = cutoff {
+                    let cutoff = cutoff.to_string();
+                    ctx.call("zremrangebyscore", &[&self.name, "-inf", &cutoff])?;
+                }
+                if let Some(n) = self.maxbuckets {
+                    let stop = (-(n as i64) - 1).to_string();
+                    ctx.call("zremrangebyrank", &[&self.name, "0", &stop])?;
+                }
+            }
+        }
+        if let Some(ref output) = self.stream {
+            if let Some(cutoff) = cutoff {
+                // entry ids are the bucket time in milliseconds
+                let end = format!("{}", (cutoff * 1000.).max(0.) as u64);
+                loop {
+                    let entries =
+                        match ctx.call("xrange", &[&output.stream, "-", &end, "COUNT", "1000"])? {
+                            RedisValue::Array(entries) => entries,
+                            _ => break,
+                        };
+                    let mut args = vec![output.stream.as_str()];
+                    for entry in &entries {
+                        if let RedisValue::Array(entry) = entry {
+                            args.push(reply_string(&entry[0])?);
+                        }
+                    }
+                    if args.len() == 1 {
+                        break;
+                    }
+                    ctx.call("xdel", &args)?;
+                }
+            }
+            if let Some(n) = self.maxbuckets {
+                ctx.call("xtrim", &[&output.stream, "MAXLEN", &n.to_string()])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The index of the newest expired bucket when the bucket at `time` is the current one, the
+    /// buckets are numbered by the time divided by the interval.
+    fn expired_bucket(&self, time: Time, interval: u32) -> i64 {
+        let interval = interval as Time;
+        let mut expired = i64::MIN;
+        if let Some(ms) = self.retention {
+            expired = expired.max(((time - ms as Time / 1000.) / interval).floor() as i64);
+        }
+        if let Some(n) = self.maxbuckets {
+            // the finalized buckets are kept besides the current one
+            let bucket = (time / interval).floor() as i64;
+            let n = n.try_into().unwrap_or(i64::MAX);
+            expired = expired.max(bucket.saturating_sub(n).saturating_sub(1));
+        }
+        expired
+    }
+
+    /// Trim the old buckets from a hash keyed by the bucket time. The expired fields are computed
+    /// from the interval, between the cutoffs of the previous bucket and the current one, the
+    /// older buckets are trimmed by the previous rollovers.
+    fn trim_hash(&self, ctx: &ModuleContext, key: &str, previous: Time) -> Result<(), RedisError> {
+        let groupby = match self.groupby {
+            Some(ref groupby) if previous > 0. => groupby,
+            _ => return Ok(()),
+        };
+        let TimeFunc::Interval(interval) = groupby.func;
+        let start = self.expired_bucket(previous, interval);
+        // no bucket is newer than the previous one
+        let end = self
+            .expired_bucket(groupby.current, interval)
+            .min((previous / interval as Time).floor() as i64);
+        if start >= end {
+            return Ok(());
+        }
+        let fields = (start + 1..=end)
+            .map(|i| (i as Time * interval as Time).to_string())
+            .collect::<Vec<_>>();
+        for chunk in fields.chunks(1000) {
+            let mut args = vec![key];
+            args.extend(chunk.iter().map(String::as_str));
+            ctx.call("hdel", &args)?;
+        }
+        Ok(())
+    }
+
+    /// Read the buckets between `from` and `to` from a hash keyed by the bucket time, in the time
+    /// order or reversed, and stop after `limit` buckets. The fields are computed from the
+    /// interval, they are only listed when the range is unbounded or wider than the hash.
+    fn hash_buckets(
+        &self,
+        ctx: &ModuleContext,
+        key: &str,
+        from: Time,
+        to: Time,
+        rev: bool,
+        limit: usize,
+    ) -> Result<Vec<(Time, String)>, RedisError> {
+        let groupby = match self.groupby {
+            Some(ref groupby) if groupby.current > 0. && limit > 0 => groupby,
+            _ => return Ok(Vec::new()),
+        };
+        let TimeFunc::Interval(interval) = groupby.func;
+        let step = interval as Time;
+        // no bucket is newer than the current one, or older than the expired ones
+        let first = (from / step)
+            .ceil()
+            .max(self.expired_bucket(groupby.current, interval) as Time + 1.);
+        let last = (to.min(groupby.current) / step).floor();
+        if first > last {
+            return Ok(Vec::new());
+        }
+        let len = match ctx.call("hlen", &[key])? {
+            RedisValue::Integer(n) => n as Time,
+            _ => return Err(RedisError::Str("expect integer reply")),
+        };
+        let mut times = Vec::new();
+        if last - first < len {
+            times.extend((first as i64..=last as i64).map(|i| i as Time * step));
+        } else if let RedisValue::Array(fields) = ctx.call("hkeys", &[key])? {
+            for field in &fields {
+                match reply_string(field)?.parse::<Time>() {
+                    Ok(time) if time.is_finite() && time >= from && time <= to => times.push(time),
+                    _ => {}
+                }
+            }
+            times.sort_by(|a, b| a.total_cmp(b));
+        }
+        if rev {
+            times.reverse();
+        }
+        let mut buckets = Vec::new();
+        for chunk in times.chunks(limit.min(1000)) {
+            let fields = chunk.iter().map(Time::to_string).collect::<Vec<_>>();
+            let mut args = vec![key];
+            args.extend(fields.iter().map(String::as_str));
+            if let RedisValue::Array(values) = ctx.call("hmget", &args)? {
+                for (&time, value) in chunk.iter().zip(&values) {
+                    if let RedisValue::Null = value {
+                        continue;
+                    }
+                    buckets.push((time, reply_string(value)?.to_owned()));
+                    if buckets.len() == limit {
+                        return Ok(buckets);
+                    }
+                }
+            }
+        }
+        Ok(buckets)
+    }
+
+    /// The views created before the view names are checked may write to the table key, their
+    /// outputs can't be deleted.
+    fn check_outputs(&self, key: &str) -> Result<(), RedisError> {
+        if self.name == key || self.states.as_deref() == Some(key) {
+            return Err(RedisError::Str("view output is the table key"));
+        }
+        Ok(())
+    }
+
+    /// Delete the output keys, including the per-bucket hashes of `FORMAT HASH` and the partial
+    /// states, the output stream is kept.
+    fn delete_outputs(&self, ctx: &ModuleContext) -> Result<(), RedisError> {
+        ctx.call("del", &[&self.name])?;
+        if let Some(ref states) = self.states {
+            ctx.call("del", &[states])?;
+        }
+        if self.format != Format::Hash || self.groupby.is_none() {
+            return Ok(());
+        }
+        let prefix = format!("{}:", self.name);
+        let pattern = format!("{}*", glob_escape(&prefix));
+        let mut cursor = "0".to_owned();
+        loop {
+            let reply = ctx.call("scan", &[&cursor, "MATCH", &pattern, "COUNT", "1000"])?;
+            let (next, keys) = match reply {
+                RedisValue::Array(mut reply) if reply.len() == 2 => match reply.pop() {
+                    Some(RedisValue::Array(keys)) => (reply_string(&reply[0])?.to_owned(), keys),
+                    _ => return Err(RedisError::Str("invalid scan reply")),
+                },
+                _ => return Err(RedisError::Str("invalid scan reply")),
+            };
+            // only the keys suffixed by a bucket time
+            let mut args = Vec::with_capacity(keys.len());
+            for key in &keys {
+                let key = reply_string(key)?;
+                if key[prefix.len()..].parse::<Time>().is_ok() {
+                    args.push(key);
+                }
+            }
+            if !args.is_empty() {
+                ctx.call("del", &args)?;
+            }
+            if next == "0" {
+                return Ok(());
+            }
+            cursor = next;
+        }
+    }
+
+    /// Write the results into a redis hash, null values are omitted.
+    fn save_hash(&self, ctx: &ModuleContext, key: String) -> Result<(), RedisError> {
+        let mut args = vec![key];
+        for agg in &self.fields {
+            if let Some(value) = agg.op.current() {
+                args.push(agg.name.clone());
+                args.push(value.to_string());
+            }
+        }
+        if args.len() > 1 {
+            let args = args.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+            ctx.call("hset", &args)?;
+        }
+        Ok(())
+    }
+
+    /// Append the finalized bucket to the output stream, with the bucket time as the entry id.
+    ///
+    /// Null values are omitted, failures are logged instead of failing the insert.
+    fn emit(&self, ctx: &ModuleContext) {
+        let (output, groupby) = match (&self.stream, &self.groupby) {
+            (Some(output), Some(groupby)) => (output, groupby),
+            _ => return,
+        };
+        let mut args = vec![output.stream.clone()];
+        if let Some(maxlen) = output.maxlen {
+            args.push("MAXLEN".to_owned());
+            args.push(maxlen.to_string());
+        }
+        args.push(format!("{}-0", (groupby.current * 1000.) as u64));
+        let n = args.len();
         for agg in &self.fields {
-            values.push(agg.op.current());
+            if let Some(value) = agg.op.current() {
+                args.push(agg.name.clone());
+                args.push(value.to_string());
+            }
+        }
+        if args.len() == n {
+            return;
+        }
+        let args = args.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        if let Err(err) = ctx.call("xadd", &args) {
+            ctx.log(
+                LogLevel::Warning,
+                &format!(
+                    "emit {} to stream {} failed: {:?}",
+                    self.name, output.stream, err
+                ),
+            );
+        }
+    }
+
+    /// Re-aggregate the buckets into coarser buckets of `interval` seconds, by merging the saved
+    /// partial states and the in-progress bucket, the coarse buckets between `from` and `to`
+    /// (inclusive) are returned in time order.
+    fn query(
+        &self,
+        ctx: &ModuleContext,
+        interval: u32,
+        from: Time,
+        to: Time,
+    ) -> Result<Vec<(Time, Vec<Option<Value>>)>, RedisError> {
+        let (states, groupby) = match (&self.states, &self.groupby) {
+            (Some(states), Some(groupby)) => (states, groupby),
+            _ => return Err(RedisError::Str("view doesn't save the partial states")),
+        };
+        match self.interval() {
+            Some(n) if n > 0 && interval > 0 && interval % n == 0 => {}
+            _ => {
+                return Err(RedisError::Str(
+                    "INTERVAL must be a multiple of the view interval",
+                ))
+            }
+        }
+        let func = TimeFunc::Interval(interval);
+        let mut buckets: BTreeMap<i64, Vec<Box<dyn AggOp>>> = BTreeMap::new();
+        let mut merge = |time: Time, ops: &[&dyn AggOp]| -> Result<(), RedisError> {
+            let time = func.apply(time);
+            if time < from || time > to {
+                return Ok(());
+            }
+            if ops.len() != self.fields.len() {
+                return Err(RedisError::Str("partial states don't match the view"));
+            }
+            let merged = match buckets.entry(time as i64) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(
+                    self.fields
+                        .iter()
+                        .map(|agg| parse_agg_type(agg.op.name()).unwrap())
+                        .collect(),
+                ),
+            };
+            for (agg, op) in merged.iter_mut().zip(ops) {
+                agg.merge(*op);
+            }
+            Ok(())
+        };
+        // the view buckets in the coarse buckets between `from` and `to`
+        let step = interval as Time;
+        let first = (from / step).ceil() * step;
+        let last = ((to / step).floor() + 1.) * step - 1.;
+        // the saved buckets are in time order, `first` and `last` depend on it
+        for (time, buf) in self.hash_buckets(ctx, states, first, last, false, usize::MAX)? {
+            // the in-progress bucket is merged from the partial states
+            if time == groupby.current {
+                continue;
+            }
+            let ops = serde_json::from_str::<Vec<Box<dyn AggOp>>>(&buf)
+                .map_err(|err| RedisError::String(format!("decode failed: {}", err)))?;
+            merge(time, &ops.iter().map(|op| op.as_ref()).collect::<Vec<_>>())?;
+        }
+        if groupby.current > 0. {
+            let ops = self
+                .fields
+                .iter()
+                .map(|agg| agg.op.as_ref())
+                .collect::<Vec<_>>();
+            merge(groupby.current, &ops)?;
+        }
+        Ok(buckets
+            .into_iter()
+            .map(|(time, ops)| (time as Time, ops.iter().map(|op| op.current()).collect()))
+            .collect())
+    }
+
+    /// Decode the saved results of a bucket into the values in the order of the fields.
+    fn decode(&self, buf: &str) -> Result<Vec<Option<Value>>, RedisError> {
+        let err = |err: serde_json::Error| RedisError::String(format!("decode failed: {}", err));
+        if self.format == Format::Array {
+            serde_json::from_str(buf).map_err(err)
+        } else {
+            let mut values =
+                serde_json::from_str::<HashMap<String, Option<Value>>>(buf).map_err(err)?;
+            Ok(self
+                .fields
+                .iter()
+                .map(|agg| values.remove(&agg.name).flatten())
+                .collect())
+        }
+    }
+
+    /// The buckets between `from` and `to` (inclusive) in time order, read from the output key,
+    /// the in-progress bucket is taken from the partial states.
+    fn range(
+        &self,
+        ctx: &ModuleContext,
+        from: Time,
+        to: Time,
+        rev: bool,
+        limit: usize,
+    ) -> Result<Vec<(Time, Vec<Option<Value>>)>, RedisError> {
+        let groupby = match self.groupby {
+            None => return Err(RedisError::Str("view is not grouped by time")),
+            Some(ref groupby) => groupby,
+        };
+        if self.format == Format::Hash {
+            return Err(RedisError::Str("range is not supported by FORMAT HASH"));
+        }
+        let mut buckets = Vec::new();
+        match self.output {
+            Output::Hash => {
+                for (time, buf) in self.hash_buckets(ctx, &self.name, from, to, rev, limit)? {
+                    buckets.push((time, self.decode(&buf)?));
+                }
+            }
+            Output::Zset => {
+                let (min, max) = (from.to_string(), to.to_string());
+                // a negative count is unlimited
+                let limit: i64 = limit.try_into().unwrap_or(-1);
+                let limit = limit.to_string();
+                let args = if rev {
+                    [
+                        "zrevrangebyscore",
+                        &self.name,
+                        &max,
+                        &min,
+                        "limit",
+                        "0",
+                        &limit,
+                    ]
+                } else {
+                    [
+                        "zrangebyscore",
+                        &self.name,
+                        &min,
+                        &max,
+                        "limit",
+                        "0",
+                        &limit,
+                    ]
+                };
+                if let RedisValue::Array(members) = ctx.call(args[0], &args[1..])? {
+                    for member in members {
+                        let member = reply_string(&member)?;
+                        if let Some((time, buf)) = member.split_once(':') {
+                            if let Ok(time) = time.parse::<Time>() {
+                                buckets.push((time, self.decode(buf)?));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let current = groupby.current;
+        if current > 0. && current >= from && current <= to {
+            // the saved copy of the in-progress bucket may be stale
+            buckets.retain(|b| b.0 != current);
+            buckets.push((
+                current,
+                self.fields.iter().map(|agg| agg.op.current()).collect(),
+            ));
+        }
+        // the in-progress bucket replaces its saved copy, or is one more than the limit
+        buckets.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if rev {
+            buckets.reverse();
+        }
+        buckets.truncate(limit);
+        Ok(buckets)
+    }
+
+    /// Publish the bucket as `time:values` on the channel `__agg__:<table>:<view>`, and fire the
+    /// `agg.bucket` module keyspace event on the output key.
+    ///
+    /// Failures are logged instead of failing the insert.
+    fn publish(&self, ctx: &ModuleContext, table: &str) {
+        let groupby = match self.groupby {
+            Some(ref groupby) if groupby.current > 0. => groupby,
+            _ => return,
+        };
+        let channel = format!("__agg__:{}:{}", table, self.name);
+        let result = self.encode().and_then(|values| {
+            let message = format!("{}:{}", groupby.current, values);
+            ctx.call("publish", &[&channel, &message])
+        });
+        if let Err(err) = result {
+            ctx.log(
+                LogLevel::Warning,
+                &format!("publish {} failed: {:?}", channel, err),
+            );
         }
-        serde_json::to_string(&values)
-            .map_err(|err| RedisError::String(format!("encode failed: {}", err)))
+        let key = if self.format == Format::Hash {
+            format!("{}:{}", self.name, groupby.current)
+        } else {
+            self.name.clone()
+        };
+        ctx.notify_keyspace_event("agg.bucket", &key);
     }
 
-    pub fn save(&self, ctx: &Context) -> Result<(), RedisError> {
+    pub fn save(&mut self, ctx: &ModuleContext) -> Result<(), RedisError> {
+        self.stats.last_save = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
         match self.groupby {
             None => {
-                ctx.call("set", &[&self.name, &self.encode()?])?;
+                if self.format == Format::Hash {
+                    self.save_hash(ctx, self.name.clone())?;
+                } else {
+                    ctx.call("set", &[&self.name, &self.encode()?])?;
+                }
             }
             Some(ref groupby) => {
                 if groupby.current > 0. {
-                    ctx.call(
-                        "hset",
-                        &[&self.name, &groupby.current.to_string(), &self.encode()?],
-                    )?;
+                    let time = groupby.current.to_string();
+                    if let Some(ref states) = self.states {
+                        let ops = self.fields.iter().map(|agg| &agg.op).collect::<Vec<_>>();
+                        let buf = serde_json::to_string(&ops)
+                            .map_err(|err| RedisError::String(format!("encode failed: {}", err)))?;
+                        ctx.call("hset", &[states, &time, &buf])?;
+                    }
+                    if self.format == Format::Hash {
+                        return self.save_hash(ctx, format!("{}:{}", self.name, time));
+                    }
+                    match self.output {
+                        Output::Hash => {
+                            ctx.call("hset", &[&self.name, &time, &self.encode()?])?;
+                        }
+                        Output::Zset => {
+                            // replace the member of the bucket
+                            let member = format!("{}:{}", time, self.encode()?);
+                            ctx.call("zremrangebyscore", &[&self.name, &time, &time])?;
+                            ctx.call("zadd", &[&self.name, &time, &member])?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq)]
+enum SqlToken {
+    Word(String),
+    Str(String),
+    Symbol(String),
+}
+
+/// Split the sql into words (identifiers, keywords and numbers), quoted strings and symbols.
+fn sql_tokens(sql: &str) -> Result<Vec<SqlToken>, RedisError> {
+    let mut tokens = Vec::new();
+    let mut chars = sql.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_alphanumeric() || c == '_' || c == '.' {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_' || c == '.') {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(SqlToken::Word(word));
+        } else if c == '\'' || c == '"' {
+            chars.next();
+            let mut string = String::new();
+            loop {
+                match chars.next() {
+                    Some(q) if q == c => break,
+                    Some(q) => string.push(q),
+                    None => return Err(RedisError::Str("unterminated string")),
                 }
             }
+            tokens.push(SqlToken::Str(string));
+        } else {
+            chars.next();
+            let mut symbol = c.to_string();
+            if (c == '>' || c == '<') && chars.peek() == Some(&'=') {
+                symbol.push('=');
+                chars.next();
+            }
+            tokens.push(SqlToken::Symbol(symbol));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parse the interval of time_bucket, like `1m` or `1 minute`, into milliseconds.
+fn sql_interval(s: &str) -> Result<u64, RedisError> {
+    let mut parts = s.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(n), Some(unit), None) => {
+            let suffix = match unit.to_lowercase().trim_end_matches('s') {
+                "millisecond" => "ms",
+                "second" => "s",
+                "minute" => "m",
+                "hour" => "h",
+                "day" => "d",
+                _ => return Err(RedisError::Str("invalid interval unit")),
+            };
+            parse_duration(&format!("{}{}", n, suffix))
+        }
+        _ => parse_duration(s),
+    }
+}
+
+/// A statement of `agg.sql` and `agg.sqlview`:
+///
+/// `[CREATE VIEW name AS] SELECT [time_bucket(interval, time) [AS alias],] aggfunc(field) [AS
+/// alias] [, ...] FROM table [WHERE time op milliseconds [AND ...]] [GROUP BY 1|alias]`
+struct SqlQuery {
+    /// the name of the view to create, or run the query once over the retained rows.
+    view: Option<String>,
+    table: String,
+    interval: Option<u32>,
+    /// the aggregations: function, field (`None` for `count(*)`) and alias.
+    fields: Vec<(String, Option<String>, Option<String>)>,
+    /// the inclusive time range of the rows in milliseconds.
+    from: u64,
+    to: u64,
+    /// the columns used by time_bucket and WHERE, which must be the time column.
+    time_columns: Vec<String>,
+}
+
+struct SqlParser {
+    tokens: Vec<SqlToken>,
+    pos: usize,
+}
+
+impl SqlParser {
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        match self.tokens.get(self.pos) {
+            Some(SqlToken::Word(word)) => word.eq_ignore_ascii_case(keyword),
+            _ => false,
+        }
+    }
+
+    fn peek_symbol(&self, symbol: &str) -> bool {
+        self.tokens.get(self.pos) == Some(&SqlToken::Symbol(symbol.to_owned()))
+    }
+
+    fn error(&self) -> RedisError {
+        match self.tokens.get(self.pos) {
+            None => RedisError::Str("syntax error: unexpected end"),
+            Some(SqlToken::Word(s)) | Some(SqlToken::Str(s)) | Some(SqlToken::Symbol(s)) => {
+                RedisError::String(format!("syntax error near {}", s))
+            }
         }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), RedisError> {
+        if !self.peek_keyword(keyword) {
+            return Err(self.error());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn symbol(&mut self, symbol: &str) -> Result<(), RedisError> {
+        if !self.peek_symbol(symbol) {
+            return Err(self.error());
+        }
+        self.pos += 1;
         Ok(())
     }
+
+    /// An identifier, a number or a quoted string.
+    fn word(&mut self) -> Result<String, RedisError> {
+        match self.tokens.get(self.pos) {
+            Some(SqlToken::Word(s)) | Some(SqlToken::Str(s)) => {
+                self.pos += 1;
+                Ok(s.clone())
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    fn alias(&mut self) -> Result<Option<String>, RedisError> {
+        if !self.peek_keyword("as") {
+            return Ok(None);
+        }
+        self.pos += 1;
+        self.word().map(Some)
+    }
+
+    fn parse(sql: &str) -> Result<SqlQuery, RedisError> {
+        let mut parser = SqlParser {
+            tokens: sql_tokens(sql)?,
+            pos: 0,
+        };
+        parser.parse_query()
+    }
+
+    fn parse_query(&mut self) -> Result<SqlQuery, RedisError> {
+        let mut view = None;
+        let mut time_columns = Vec::new();
+        if self.peek_keyword("create") {
+            self.pos += 1;
+            self.keyword("view")?;
+            view = Some(self.word()?);
+            self.keyword("as")?;
+        }
+        self.keyword("select")?;
+        let mut interval = None;
+        // the position and alias of the time_bucket column, referred by GROUP BY
+        let mut bucket = None;
+        let mut fields = Vec::new();
+        let mut column = 0;
+        loop {
+            column += 1;
+            let func = self.word()?;
+            self.symbol("(")?;
+            if func.eq_ignore_ascii_case("time_bucket") {
+                if interval.is_some() {
+                    return Err(RedisError::Str("duplicated time_bucket"));
+                }
+                let ms = sql_interval(&self.word()?)?;
+                if ms == 0 || ms % 1000 != 0 || ms / 1000 > 3600 * 24 * 365 * 10 {
+                    return Err(RedisError::Str("Invalid time interval"));
+                }
+                interval = Some((ms / 1000) as u32);
+                self.symbol(",")?;
+                time_columns.push(self.word()?);
+                self.symbol(")")?;
+                bucket = Some((column.to_string(), self.alias()?));
+            } else {
+                let field = if self.peek_symbol("*") && func.eq_ignore_ascii_case("count") {
+                    // count the rows by the time column which is never null
+                    self.pos += 1;
+                    None
+                } else {
+                    Some(self.word()?)
+                };
+                self.symbol(")")?;
+                fields.push((func.to_lowercase(), field, self.alias()?));
+            }
+            if !self.peek_symbol(",") {
+                break;
+            }
+            self.pos += 1;
+        }
+        self.keyword("from")?;
+        let table = self.word()?;
+        let (mut from, mut to) = (0, u64::MAX);
+        if self.peek_keyword("where") {
+            if view.is_some() {
+                return Err(RedisError::Str("WHERE can't be used with CREATE VIEW"));
+            }
+            loop {
+                self.pos += 1;
+                time_columns.push(self.word()?);
+                let op = match self.tokens.get(self.pos) {
+                    Some(SqlToken::Symbol(op)) => op.clone(),
+                    _ => return Err(self.error()),
+                };
+                self.pos += 1;
+                let ms = parse_integer(&self.word()?)?.max(0) as u64;
+                match op.as_str() {
+                    ">" => from = from.max(ms.saturating_add(1)),
+                    ">=" => from = from.max(ms),
+                    "<" => to = to.min(ms.saturating_sub(1)),
+                    "<=" => to = to.min(ms),
+                    "=" => {
+                        from = from.max(ms);
+                        to = to.min(ms);
+                    }
+                    _ => return Err(RedisError::String(format!("invalid operator {}", op))),
+                }
+                if !self.peek_keyword("and") {
+                    break;
+                }
+            }
+        }
+        if self.peek_keyword("group") {
+            self.pos += 1;
+            self.keyword("by")?;
+            let key = self.word()?;
+            match bucket {
+                Some((ref position, ref alias))
+                    if &key == position || alias.as_ref() == Some(&key) => {}
+                _ => {
+                    return Err(RedisError::Str(
+                        "GROUP BY must refer to the time_bucket column",
+                    ))
+                }
+            }
+        } else if bucket.is_some() {
+            return Err(RedisError::Str("time_bucket requires GROUP BY"));
+        }
+        if self.pos < self.tokens.len() {
+            return Err(self.error());
+        }
+        Ok(SqlQuery {
+            view,
+            table,
+            interval,
+            fields,
+            from,
+            to,
+            time_columns,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct AggTable {
+    /// the key name of the table, used to find the table from module level callbacks.
+    #[serde(default)]
+    name: String,
     fields: Vec<String>,
+    /// default values of the columns, used when the field is missing in `agg.insertmap`.
+    #[serde(default)]
+    defaults: Vec<Option<Value>>,
     #[serde(skip)]
     fields_by_name: HashMap<String, usize>,
     views: Vec<AggView>,
     last_id: StreamID,
+    #[serde(default)]
+    source: Option<StreamSource>,
+    /// how long the raw rows are retained for `agg.rebuild`, in milliseconds.
+    #[serde(default)]
+    retain: Option<u64>,
+    /// the retained raw rows, including the time column.
+    #[serde(default)]
+    rows: VecDeque<(StreamID, Vec<Option<Value>>)>,
+    /// the interval of the periodic save in milliseconds, overrides `SAVE_INTERVAL`.
+    #[serde(default)]
+    save_every: Option<u64>,
 
+    /// the db of the key, unknown for the tables loaded from rdb until the loading ends.
     #[serde(skip)]
-    timer: u64,
+    db: Option<c_int>,
+    /// the key of the table in `TABLES`.
+    #[serde(skip)]
+    serial: u64,
 }
 
 impl AggTable {
-    pub fn new(fields: Vec<String>) -> AggTable {
+    pub fn new(name: String, fields: Vec<String>, defaults: Vec<Option<Value>>) -> AggTable {
         let mut fields_by_name = HashMap::new();
         for (i, field) in fields.iter().enumerate() {
             fields_by_name.insert(field.clone(), i);
         }
 
         return AggTable {
+            name,
             fields,
+            defaults,
             fields_by_name,
             views: Vec::new(),
             last_id: StreamID::new(),
+            source: None,
+            retain: None,
+            rows: VecDeque::new(),
+            save_every: None,
 
-            timer: 0,
+            db: None,
+            serial: 0,
         };
     }
 
-    pub fn parse_agg_field(&self, func: &String, field: &String) -> Result<AggField, RedisError> {
-        let op = parse_agg_type(func).ok_or(RedisError::Str("invalid aggregate operation"))?;
-        let index = self
-            .fields_by_name
-            .get(field)
-            .ok_or(RedisError::Str("invalid field name"))?;
-        return Ok(AggField { index: *index, op });
-    }
-
+    fn save_rdb(&self, rdb: &mut RdbWriter) {
+        rdb.string(&self.name);
+        rdb.strings(&self.fields);
+        rdb.unsigned(self.defaults.len() as u64);
+        for value in &self.defaults {
+            rdb.option_double(*value);
+        }
+        rdb.unsigned(self.views.len() as u64);
+        for view in &self.views {
+            view.save_rdb(rdb);
+        }
+        rdb.stream_id(&self.last_id);
+        rdb.bool(self.source.is_some());
+        if let Some(ref source) = self.source {
+            rdb.string(&source.stream);
+            rdb.strings(&source.fields);
+            rdb.stream_id(&source.last_id);
+        }
+        rdb.option_unsigned(self.retain);
+        rdb.unsigned(self.rows.len() as u64);
+        for (id, row) in &self.rows {
+            rdb.stream_id(id);
+            rdb.unsigned(row.len() as u64);
+            for value in row {
+                rdb.option_double(*value);
+            }
+        }
+        rdb.option_unsigned(self.save_every);
+    }
+
+    fn load_rdb(rdb: &mut RdbReader) -> Result<AggTable, RedisError> {
+        let name = rdb.string()?;
+        let fields = rdb.strings()?;
+        let defaults = (0..rdb.len()?)
+            .map(|_| rdb.option_double())
+            .collect::<Result<_, _>>()?;
+        let mut table = AggTable::new(name, fields, defaults);
+        for _ in 0..rdb.len()? {
+            table.views.push(AggView::load_rdb(rdb)?);
+        }
+        table.last_id = rdb.stream_id()?;
+        if rdb.bool()? {
+            table.source = Some(StreamSource {
+                stream: rdb.string()?,
+                fields: rdb.strings()?,
+                last_id: rdb.stream_id()?,
+            });
+        }
+        table.retain = rdb.option_unsigned()?;
+        for _ in 0..rdb.len()? {
+            let id = rdb.stream_id()?;
+            let row = (0..rdb.len()?)
+                .map(|_| rdb.option_double())
+                .collect::<Result<_, _>>()?;
+            table.rows.push_back((id, row));
+        }
+        table.save_every = rdb.option_unsigned()?;
+        Ok(table)
+    }
+
+    /// Check the invariants the commands rely on, for the states which are not built by the
+    /// commands, from rdb, `agg.restore` and `agg.import`.
+    fn validate(&self) -> Result<(), RedisError> {
+        let columns = self.fields.iter().collect::<BTreeSet<_>>();
+        if self.fields.is_empty() || columns.len() != self.fields.len() {
+            return Err(RedisError::Str("invalid columns"));
+        }
+        if self.defaults.len() > self.fields.len() {
+            return Err(RedisError::Str("defaults don't match the columns"));
+        }
+        if self
+            .rows
+            .iter()
+            .any(|(_, row)| row.len() != self.fields.len())
+        {
+            return Err(RedisError::Str("retained rows don't match the columns"));
+        }
+        if let Some(ref source) = self.source {
+            let columns = &self.fields[1..];
+            if source.fields.iter().any(|f| !columns.contains(f)) {
+                return Err(RedisError::Str("source fields don't match the columns"));
+            }
+            if self.streams_to(&source.stream) {
+                return Err(RedisError::Str("source is the STREAM of a view"));
+            }
+        }
+        let mut names = BTreeSet::new();
+        for (i, view) in self.views.iter().enumerate() {
+            let err = |msg: &str| Err(RedisError::String(format!("view {}: {}", view.name, msg)));
+            if !names.insert(view.name.as_str()) {
+                return err("duplicated view name");
+            }
+            if let Some(ref groupby) = view.groupby {
+                let TimeFunc::Interval(n) = groupby.func;
+                if n == 0 {
+                    return err("invalid interval");
+                }
+                // the bucket times are multiples of the interval
+                if !groupby.current.is_finite() || groupby.current % n as Time != 0. {
+                    return err("invalid current bucket");
+                }
+            }
+            // the source view of a rollup view is always an earlier one
+            let width = match view.from {
+                None => self.fields.len(),
+                Some(ref from) => match self.views[..i].iter().find(|v| &v.name == from) {
+                    None => return err("source view not exist"),
+                    Some(source) => {
+                        let mismatched = view.fields.iter().any(|agg| {
+                            source
+                                .fields
+                                .get(agg.index)
+                                .map_or(false, |f| f.op.name() != agg.op.name())
+                        });
+                        if mismatched {
+                            return err("fields don't match the source view");
+                        }
+                        source.fields.len()
+                    }
+                },
+            };
+            if view.fields.iter().any(|agg| agg.index >= width) {
+                return err("field index out of range");
+            }
+        }
+        Ok(())
+    }
+
+    /// Restore the states which are not serialized, after the table is loaded.
+    fn restore(&mut self) {
+        self.index_fields();
+        self.default_field_names();
+    }
+
+    /// Name the fields of the views created before the aliases are supported, a repeated name
+    /// gets the position of the field as a suffix, like `sum_price_1`. The field of a rollup view
+    /// takes the name of the source field it merges.
+    fn default_field_names(&mut self) {
+        for j in 0..self.views.len() {
+            let source = self.views[j]
+                .from
+                .as_ref()
+                .and_then(|from| self.views[..j].iter().position(|v| &v.name == from));
+            let mut names = BTreeSet::new();
+            for i in 0..self.views[j].fields.len() {
+                let agg = &self.views[j].fields[i];
+                let mut name = agg.name.clone();
+                if name.is_empty() {
+                    name = match source {
+                        Some(k) => self.views[k].fields[agg.index].name.clone(),
+                        None => format!("{}_{}", agg.op.name(), self.fields[agg.index]),
+                    };
+                    if names.contains(&name) {
+                        name = format!("{}_{}", name, i);
+                    }
+                }
+                names.insert(name.clone());
+                self.views[j].fields[i].name = name;
+            }
+        }
+    }
+
+    pub fn parse_agg_field(&self, func: &String, field: &String) -> Result<AggField, RedisError> {
+        let op = parse_agg_type(func).ok_or(RedisError::Str("invalid aggregate operation"))?;
+        let index = self
+            .fields_by_name
+            .get(field)
+            .ok_or(RedisError::Str("invalid field name"))?;
+        return Ok(AggField {
+            index: *index,
+            op,
+            name: format!("{}_{}", func, field),
+        });
+    }
+
     pub fn parse_view(
         &self,
+        key: &str,
         name: String,
         interval: Option<u32>,
         args: &[String],
     ) -> Result<AggView, RedisError> {
+        if name == key {
+            return Err(RedisError::Str("view name can't be the table key"));
+        }
         let mut fields = Vec::new();
-        for chunk in args.chunks_exact(2) {
-            fields.push(self.parse_agg_field(&chunk[0], &chunk[1])?);
+        let mut i = 0;
+        while i + 1 < args.len() {
+            let mut field = self.parse_agg_field(&args[i], &args[i + 1])?;
+            i += 2;
+            if i + 1 < args.len() && args[i].to_lowercase() == "as" {
+                field.name = args[i + 1].clone();
+                i += 2;
+            }
+            if fields.iter().any(|f: &AggField| f.name == field.name) {
+                return Err(RedisError::String(format!(
+                    "duplicated field name {}, use AS alias",
+                    field.name
+                )));
+            }
+            fields.push(field);
         }
         return Ok(AggView {
             name,
@@ -564,172 +2421,2079 @@ impl AggTable {
                 current: 0.,
                 func: TimeFunc::Interval(i),
             }),
+            stream: None,
+            output: Output::Hash,
+            format: Format::Array,
+            retention: None,
+            maxbuckets: None,
+            from: None,
+            states: None,
+            nosave: false,
+            stats: ViewStats::default(),
         });
     }
 
-    pub fn add_view(&mut self, args: &[String]) -> RedisResult {
+    /// Parse the fields of a rollup view, each field must match a field of the source view with
+    /// the same aggregate operation, by the field name or the column name.
+    fn parse_rollup_fields(
+        &self,
+        source: &AggView,
+        args: &[String],
+    ) -> Result<Vec<AggField>, RedisError> {
+        let mut fields = Vec::new();
+        let mut i = 0;
+        while i + 1 < args.len() {
+            let (func, field) = (&args[i], &args[i + 1]);
+            let op = parse_agg_type(func).ok_or(RedisError::Str("invalid aggregate operation"))?;
+            let default_name = format!("{}_{}", func, field);
+            let index = source
+                .fields
+                .iter()
+                .position(|f| {
+                    f.op.name() == func.as_str() && (&f.name == field || f.name == default_name)
+                })
+                .ok_or_else(|| {
+                    RedisError::String(format!(
+                        "field {} {} not found in the source view",
+                        func, field
+                    ))
+                })?;
+            let mut agg = AggField {
+                index,
+                op,
+                name: default_name,
+            };
+            i += 2;
+            if i + 1 < args.len() && args[i].to_lowercase() == "as" {
+                agg.name = args[i + 1].clone();
+                i += 2;
+            }
+            if fields.iter().any(|f: &AggField| f.name == agg.name) {
+                return Err(RedisError::String(format!(
+                    "duplicated field name {}, use AS alias",
+                    agg.name
+                )));
+            }
+            fields.push(agg);
+        }
+        Ok(fields)
+    }
+
+    pub fn add_view(&mut self, key: &str, args: &[String]) -> RedisResult {
         if args.len() <= 1 {
             return Err(RedisError::WrongArity);
         }
         let name = args[0].clone();
-        let (interval, args) = if args[1].to_lowercase() == "interval" {
-            if args.len() <= 3 {
+        let mut interval = None;
+        let mut stream: Option<StreamOutput> = None;
+        let mut output = Output::Hash;
+        let mut format = Format::Array;
+        let mut retention = None;
+        let mut maxbuckets = None;
+        let mut from: Option<String> = None;
+        let mut states = None;
+        let mut nosave = false;
+        let mut args = &args[1..];
+        loop {
+            let option = match args.first() {
+                Some(option) => option.to_lowercase(),
+                None => break,
+            };
+            if option == "nosave" {
+                nosave = true;
+                args = &args[1..];
+                continue;
+            }
+            if ![
+                "interval",
+                "stream",
+                "maxlen",
+                "output",
+                "format",
+                "retention",
+                "maxbuckets",
+                "from",
+                "states",
+            ]
+            .contains(&option.as_str())
+            {
+                break;
+            }
+            if args.len() <= 2 {
                 return Err(RedisError::WrongArity);
             }
-            let i = parse_integer(&args[2])?;
-            let i = i
-                .try_into()
-                .map_err(|err: TryFromIntError| RedisError::String(err.to_string()))?;
-            if i > 3600 * 24 * 365 * 10 {
-                return Err(RedisError::Str("Invalid time interval"));
+            match option.as_str() {
+                "interval" => {
+                    let i = parse_integer(&args[1])?;
+                    let i = i
+                        .try_into()
+                        .map_err(|err: TryFromIntError| RedisError::String(err.to_string()))?;
+                    if i == 0 || i > 3600 * 24 * 365 * 10 {
+                        return Err(RedisError::Str("Invalid time interval"));
+                    }
+                    interval = Some(i);
+                }
+                "output" => {
+                    output = match args[1].to_lowercase().as_str() {
+                        "hash" => Output::Hash,
+                        "zset" => Output::Zset,
+                        _ => return Err(RedisError::Str("invalid output type")),
+                    };
+                }
+                "retention" => {
+                    retention = Some(parse_duration(&args[1])?);
+                }
+                "from" => {
+                    from = Some(args[1].clone());
+                }
+                "states" => {
+                    states = Some(args[1].clone());
+                }
+                "maxbuckets" => {
+                    let n = parse_integer(&args[1])?;
+                    if n <= 0 {
+                        return Err(RedisError::Str("MAXBUCKETS must be positive"));
+                    }
+                    maxbuckets = Some(n as u64);
+                }
+                "format" => {
+                    format = match args[1].to_lowercase().as_str() {
+                        "array" => Format::Array,
+                        "object" => Format::Object,
+                        "hash" => Format::Hash,
+                        _ => return Err(RedisError::Str("invalid format")),
+                    };
+                }
+                "stream" => {
+                    stream = Some(StreamOutput {
+                        stream: args[1].clone(),
+                        maxlen: None,
+                    });
+                }
+                _ => {
+                    let maxlen = parse_integer(&args[1])?
+                        .try_into()
+                        .map_err(|err: TryFromIntError| RedisError::String(err.to_string()))?;
+                    stream
+                        .as_mut()
+                        .ok_or(RedisError::Str("MAXLEN requires STREAM"))?
+                        .maxlen = Some(maxlen);
+                }
             }
-            (Some(i), &args[3..])
-        } else {
-            (None, &args[1..])
-        };
+            args = &args[2..];
+        }
+        // at least one aggregation after the options
+        if args.len() < 2 {
+            return Err(RedisError::WrongArity);
+        }
+        if stream.is_some() && interval.is_none() {
+            return Err(RedisError::Str("STREAM output requires INTERVAL"));
+        }
+        if states.is_some() && interval.is_none() {
+            return Err(RedisError::Str("STATES requires INTERVAL"));
+        }
+        if output != Output::Hash && interval.is_none() {
+            return Err(RedisError::Str("OUTPUT requires INTERVAL"));
+        }
+        if output != Output::Hash && format == Format::Hash {
+            return Err(RedisError::Str("FORMAT HASH can't be used with OUTPUT"));
+        }
+        if retention.is_some() || maxbuckets.is_some() {
+            if interval.is_none() {
+                return Err(RedisError::Str("RETENTION and MAXBUCKETS require INTERVAL"));
+            }
+            if format == Format::Hash {
+                return Err(RedisError::Str(
+                    "RETENTION and MAXBUCKETS can't be used with FORMAT HASH",
+                ));
+            }
+        }
 
-        self.views.push(self.parse_view(name, interval, args)?);
+        if self.views.iter().any(|v| v.name == name) {
+            return Err(RedisError::Str("view already exist"));
+        }
+        if let (Some(out), Some(source)) = (&stream, &self.source) {
+            if out.stream == source.stream {
+                return Err(RedisError::Str("STREAM can't be the source of the table"));
+            }
+        }
+        if states.as_deref() == Some(key) {
+            return Err(RedisError::Str("STATES can't be the table key"));
+        }
+        let mut view = match from {
+            None => self.parse_view(key, name, interval, args)?,
+            Some(from) => {
+                let source = self
+                    .views
+                    .iter()
+                    .find(|v| v.name == from)
+                    .ok_or(RedisError::Str("source view not exist"))?;
+                let source_interval = match source.groupby {
+                    Some(GroupState {
+                        func: TimeFunc::Interval(n),
+                        ..
+                    }) => n,
+                    None => return Err(RedisError::Str("source view must have INTERVAL")),
+                };
+                match interval {
+                    Some(n) if n % source_interval == 0 => {}
+                    _ => {
+                        return Err(RedisError::Str(
+                            "INTERVAL must be a multiple of the source view interval",
+                        ))
+                    }
+                }
+                let fields = self.parse_rollup_fields(source, args)?;
+                let mut view = self.parse_view(key, name, interval, &[])?;
+                view.fields = fields;
+                view.from = Some(from);
+                view
+            }
+        };
+        view.stream = stream;
+        view.output = output;
+        view.format = format;
+        view.retention = retention;
+        view.maxbuckets = maxbuckets;
+        view.states = states;
+        view.nosave = nosave;
+        self.views.push(view);
         REDIS_OK
     }
 
-    pub fn update(&mut self, ctx: &Context, time: &str, args: &[String]) -> RedisResult {
-        if args.len() + 1 != self.fields.len() {
-            return Err(RedisError::WrongArity);
+    /// Parse column definitions: `field [DEFAULT value] [field [DEFAULT value] ...]`, the columns
+    /// end at the first table option, the rest arguments are returned.
+    pub fn parse_columns(
+        args: &[String],
+    ) -> Result<(Vec<String>, Vec<Option<Value>>, &[String]), RedisError> {
+        let mut fields = Vec::new();
+        let mut defaults = Vec::new();
+        let mut i = 0;
+        while i < args.len() {
+            if TABLE_OPTIONS.contains(&args[i].to_lowercase().as_str()) {
+                break;
+            }
+            if args[i].to_lowercase() == "default" {
+                if fields.is_empty() || i + 1 >= args.len() {
+                    return Err(RedisError::WrongArity);
+                }
+                *defaults.last_mut().unwrap() = Some(parse_float(&args[i + 1])?);
+                i += 2;
+            } else {
+                if fields.contains(&args[i]) {
+                    return Err(RedisError::Str("duplicated field name"));
+                }
+                fields.push(args[i].clone());
+                defaults.push(None);
+                i += 1;
+            }
         }
-        let parse_err = |e: ParseIntError| RedisError::String(e.to_string());
-        let (ms, seq) = match time.find('-') {
-            None => (time.parse::<u64>().map_err(parse_err)?, None),
-            Some(i) => (
-                time[..i].parse::<u64>().map_err(parse_err)?,
-                Some(time[i + 1..].parse::<u64>().map_err(parse_err)?),
-            ),
-        };
-        let id = match seq {
+        Ok((fields, defaults, &args[i..]))
+    }
+
+    /// Parse the table options: `[RETAIN duration]`
+    pub fn set_options(&mut self, mut args: &[String]) -> Result<(), RedisError> {
+        while let Some(option) = args.first() {
+            let option = option.to_lowercase();
+            if args.len() < 2 {
+                return Err(RedisError::WrongArity);
+            }
+            match option.as_str() {
+                "retain" => self.retain = Some(parse_duration(&args[1])?),
+                "saveevery" => {
+                    let ms = parse_duration(&args[1])?;
+                    if ms == 0 {
+                        return Err(RedisError::Str("SAVEEVERY must be positive"));
+                    }
+                    self.save_every = Some(ms);
+                }
+                _ => return Err(RedisError::Str("syntax error")),
+            }
+            args = &args[2..];
+        }
+        Ok(())
+    }
+
+    /// The interval of the periodic save.
+    fn save_interval(&self) -> Duration {
+        let ms = self
+            .save_every
+            .unwrap_or_else(|| SAVE_INTERVAL.load(Ordering::Relaxed));
+        Duration::from_millis(ms)
+    }
+
+    /// Parse the input time into a new id which is bigger than `last_id`.
+    fn parse_id(&self, time: &str) -> Result<StreamID, RedisError> {
+        let (ms, seq) = StreamID::parse(time)?;
+        match seq {
             None => {
-                if !self.last_id.increment(ms) {
+                let mut id = self.last_id.clone();
+                if !id.increment(ms) {
                     return Err(RedisError::Str("input time is smaller"));
                 }
-                self.last_id.clone()
+                Ok(id)
             }
             Some(seq) => {
                 let id = StreamID { ms, seq };
                 if id <= self.last_id {
                     return Err(RedisError::Str("input time is smaller"));
                 }
+                Ok(id)
+            }
+        }
+    }
+
+    /// Apply a row to all the views, the row doesn't include the time column.
+    fn insert_row(
+        &mut self,
+        ctx: &ModuleContext,
+        key: &str,
+        id: StreamID,
+        mut row: Vec<Option<Value>>,
+    ) -> RedisResult {
+        self.last_id = id.clone();
+        let time = id.ms as Value / 1000.;
+        row.insert(0, Some(time));
+        if let Some(retain) = self.retain {
+            self.rows.push_back((id.clone(), row.clone()));
+            while let Some((first, _)) = self.rows.front() {
+                if first.ms.saturating_add(retain) >= id.ms {
+                    break;
+                }
+                self.rows.pop_front();
+            }
+        }
+        for i in 0..self.views.len() {
+            if self.views[i].from.is_none() {
+                self.update_view(ctx, Some(key), i, time, Input::Row(&row))?;
+            }
+        }
+        Ok(RedisValue::SimpleString(id.into()))
+    }
+
+    /// Update a view, the finalized bucket is merged into the rollup views before it's reset, and
+    /// published on the channel of the table `key` if it's given.
+    fn update_view(
+        &mut self,
+        ctx: &ModuleContext,
+        key: Option<&str>,
+        i: usize,
+        time: Time,
+        input: Input,
+    ) -> RedisResult {
+        self.rollover(ctx, key, i, time)?;
+        match input {
+            Input::Row(row) => self.views[i].update(ctx, time, row)?,
+            Input::Merge(source) => {
+                let (left, right) = self.views.split_at_mut(i);
+                right[0].merge(ctx, time, &left[source])?
+            }
+        }
+        REDIS_OK
+    }
+
+    /// Publish the current bucket of a view and merge it into the rollup views, if the time starts
+    /// a new bucket, before the view moves to it.
+    fn rollover(
+        &mut self,
+        ctx: &ModuleContext,
+        key: Option<&str>,
+        i: usize,
+        time: Time,
+    ) -> Result<(), RedisError> {
+        if self.views[i].is_rollover(time) {
+            let bucket = self.views[i].groupby.as_ref().unwrap().current;
+            if let Some(key) = key {
+                self.views[i].publish(ctx, key);
+            }
+            // rollup views are always created after the source view
+            for j in i + 1..self.views.len() {
+                if self.views[j].from.as_deref() == Some(self.views[i].name.as_str()) {
+                    self.update_view(ctx, key, j, bucket, Input::Merge(i))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A row of the default values, without the time column.
+    fn default_row(&self) -> Vec<Option<Value>> {
+        (1..self.fields.len())
+            .map(|i| self.defaults.get(i).cloned().flatten())
+            .collect()
+    }
+
+    /// Insert a row by position, the trailing columns which have default values can be omitted,
+    /// so the producers keep working after new columns are added.
+    pub fn update(
+        &mut self,
+        ctx: &ModuleContext,
+        key: &str,
+        time: &str,
+        args: &[String],
+    ) -> RedisResult {
+        let mut row = self.default_row();
+        if args.len() > row.len() || row[args.len()..].iter().any(Option::is_none) {
+            return Err(RedisError::WrongArity);
+        }
+        let id = self.parse_id(time)?;
+        for (value, arg) in row.iter_mut().zip(args) {
+            *value = Some(parse_float(arg)?);
+        }
+        self.insert_row(ctx, key, id, row)
+    }
+
+    /// Insert multiple rows, the whole batch is validated before any row is applied. The rows are
+    /// split by the number of columns, so every column must be given, the defaults don't apply.
+    pub fn update_batch(&mut self, ctx: &ModuleContext, key: &str, args: &[String]) -> RedisResult {
+        let width = self.fields.len();
+        if args.is_empty() || args.len() % width != 0 {
+            return Err(RedisError::WrongArity);
+        }
+        // each id is parsed after the previous row, `last_id` is put back before applying
+        let last_id = self.last_id.clone();
+        let rows = args
+            .chunks_exact(width)
+            .map(|chunk| {
+                let id = self.parse_id(&chunk[0])?;
+                let row = chunk[1..]
+                    .iter()
+                    .map(|s| parse_float(&s).map(Some))
+                    .collect::<Result<Vec<_>, _>>()?;
                 self.last_id = id.clone();
-                id
+                Ok((id, row))
+            })
+            .collect::<Result<Vec<_>, RedisError>>();
+        self.last_id = last_id;
+        let rows = rows?;
+        let mut ids = Vec::with_capacity(rows.len());
+        for (i, (id, row)) in rows.into_iter().enumerate() {
+            match self.insert_row(ctx, key, id, row) {
+                Ok(id) => ids.push(id),
+                Err(err) => {
+                    // replicate the applied rows, the command isn't replicated on error
+                    if i > 0 {
+                        let mut applied = vec![key];
+                        applied.extend(args[..i * width].iter().map(String::as_str));
+                        raw::replicate(ctx.raw, "agg.minsert", &applied);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(RedisValue::Array(ids))
+    }
+
+    /// Insert by field names, missing fields take the column default or null.
+    pub fn update_map(
+        &mut self,
+        ctx: &ModuleContext,
+        key: &str,
+        time: &str,
+        args: &[String],
+    ) -> RedisResult {
+        if args.len() % 2 != 0 {
+            return Err(RedisError::WrongArity);
+        }
+        let mut row = self.default_row();
+        for chunk in args.chunks_exact(2) {
+            let index = *self
+                .fields_by_name
+                .get(&chunk[0])
+                .ok_or(RedisError::Str("invalid field name"))?;
+            if index == 0 {
+                return Err(RedisError::Str("can't set the time field"));
+            }
+            row[index - 1] = Some(parse_float(&chunk[1])?);
+        }
+        let id = self.parse_id(time)?;
+        self.insert_row(ctx, key, id, row)
+    }
+
+    /// Subscribe the table to a redis stream, and consume the entries after `last_id`.
+    pub fn set_source(
+        &mut self,
+        ctx: &ModuleContext,
+        key: &str,
+        stream: &str,
+        fields: &[String],
+    ) -> RedisResult {
+        for field in fields {
+            match self.fields_by_name.get(field) {
+                None => return Err(RedisError::Str("invalid field name")),
+                Some(0) => return Err(RedisError::Str("can't set the time field")),
+                Some(_) => {}
+            }
+        }
+        // the entries emitted by a view would be consumed while the table is updated
+        if self.streams_to(stream) {
+            return Err(RedisError::Str("source can't be the STREAM of a view"));
+        }
+        if let (Some(db), Some(source)) = (self.db, self.source.take()) {
+            unregister_source(db, &source.stream, &self.name);
+        }
+        let db = ctx.selected_db();
+        self.db = Some(db);
+        self.source = Some(StreamSource {
+            stream: stream.to_owned(),
+            fields: fields.to_vec(),
+            last_id: self.last_id.clone(),
+        });
+        register_source(db, stream, &self.name);
+        self.consume_source(ctx, key, false)?;
+        REDIS_OK
+    }
+
+    /// Whether a view of the table emits to the stream.
+    fn streams_to(&self, stream: &str) -> bool {
+        self.views
+            .iter()
+            .any(|v| v.stream.as_ref().map_or(false, |s| s.stream == stream))
+    }
+
+    /// Convert a stream entry into a row, the fields not subscribed are ignored.
+    fn source_row(&self, values: &[RedisValue]) -> Result<Vec<Option<Value>>, RedisError> {
+        let source = self.source.as_ref().ok_or(RedisError::Str("no source"))?;
+        let mut row = self.default_row();
+        for chunk in values.chunks_exact(2) {
+            let field = reply_string(&chunk[0])?;
+            if !source.fields.iter().any(|f| f == field) {
+                continue;
             }
+            let index = *self
+                .fields_by_name
+                .get(field)
+                .ok_or(RedisError::Str("invalid field name"))?;
+            row[index - 1] = Some(parse_float(reply_string(&chunk[1])?)?);
+        }
+        Ok(row)
+    }
+
+    /// Set the db of the key, the source is subscribed in the db.
+    fn set_db(&mut self, db: c_int) {
+        if let (Some(old), Some(source)) = (self.db, &self.source) {
+            unregister_source(old, &source.stream, &self.name);
+        }
+        self.db = Some(db);
+        if let Some(ref source) = self.source {
+            register_source(db, &source.stream, &self.name);
+        }
+        update_entry(self);
+    }
+
+    /// Replicate a row as `agg.insertmap` with the exact id, the null columns are left out.
+    fn replicate_row(&self, ctx: &ModuleContext, key: &str, id: &StreamID, row: &[Option<Value>]) {
+        let time: String = id.clone().into();
+        let values = row
+            .iter()
+            .enumerate()
+            .filter_map(|(i, value)| Some((&self.fields[i + 1], (*value)?.to_string())))
+            .collect::<Vec<_>>();
+        let mut args = vec![key, time.as_str()];
+        for (field, value) in &values {
+            args.push(field);
+            args.push(value);
+        }
+        raw::replicate(ctx.raw, "agg.insertmap", &args);
+    }
+
+    /// Consume the new entries of the source stream.
+    ///
+    /// Entries which are not bigger than `last_id` are skipped, invalid entries are logged and
+    /// skipped, so a bad entry won't block the stream. The cursor only moves after the entry is
+    /// applied. With `replicate`, the applied rows are replicated as `agg.insertmap`, since the
+    /// replicas don't consume the streams by themselves.
+    pub fn consume_source(
+        &mut self,
+        ctx: &ModuleContext,
+        key: &str,
+        replicate: bool,
+    ) -> Result<(), RedisError> {
+        let stream = match self.source {
+            None => return Ok(()),
+            Some(ref source) => source.stream.clone(),
         };
-        let mut args = args
+        let count = SOURCE_BATCH.to_string();
+        loop {
+            let start: String = self.source.as_ref().unwrap().last_id.successor().into();
+            let entries = match ctx.call("xrange", &[&stream, &start, "+", "COUNT", &count])? {
+                RedisValue::Array(entries) => entries,
+                _ => return Err(RedisError::Str("invalid xrange reply")),
+            };
+            for entry in &entries {
+                let (id, values) = match entry {
+                    RedisValue::Array(entry) if entry.len() == 2 => (&entry[0], &entry[1]),
+                    _ => return Err(RedisError::Str("invalid xrange reply")),
+                };
+                let (ms, seq) = StreamID::parse(reply_string(id)?)?;
+                let id = StreamID {
+                    ms,
+                    seq: seq.unwrap_or(0),
+                };
+                let row = match values {
+                    RedisValue::Array(values) => self.source_row(values),
+                    _ => Err(RedisError::Str("invalid xrange reply")),
+                };
+                match row {
+                    Ok(row) => {
+                        if id > self.last_id {
+                            self.insert_row(ctx, key, id.clone(), row.clone())?;
+                            if replicate {
+                                self.replicate_row(ctx, key, &id, &row);
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        let id: String = id.clone().into();
+                        ctx.log(
+                            LogLevel::Warning,
+                            &format!("skip stream entry {} {}: {:?}", stream, id, err),
+                        );
+                    }
+                }
+                self.source.as_mut().unwrap().last_id = id;
+            }
+            if entries.len() < SOURCE_BATCH {
+                return Ok(());
+            }
+        }
+    }
+
+    fn index_fields(&mut self) {
+        self.fields_by_name.clear();
+        for (i, field) in self.fields.iter().enumerate() {
+            self.fields_by_name.insert(field.clone(), i);
+        }
+    }
+
+    /// Append a column, the retained rows take the default value.
+    pub fn add_column(&mut self, name: &str, default: Option<Value>) -> RedisResult {
+        if self.fields_by_name.contains_key(name) {
+            return Err(RedisError::Str("duplicated field name"));
+        }
+        self.defaults.resize(self.fields.len(), None);
+        self.fields.push(name.to_owned());
+        self.defaults.push(default);
+        for (_, row) in &mut self.rows {
+            row.push(default);
+        }
+        self.index_fields();
+        REDIS_OK
+    }
+
+    /// Remove a column which is not used by the views or the stream source, the column indexes
+    /// of the views are remapped.
+    pub fn drop_column(&mut self, name: &str) -> RedisResult {
+        let index = *self
+            .fields_by_name
+            .get(name)
+            .ok_or(RedisError::Str("invalid field name"))?;
+        if index == 0 {
+            return Err(RedisError::Str("can't drop the time field"));
+        }
+        for view in &self.views {
+            if view.from.is_none() && view.fields.iter().any(|agg| agg.index == index) {
+                return Err(RedisError::String(format!(
+                    "field is used by view {}",
+                    view.name
+                )));
+            }
+        }
+        if let Some(ref source) = self.source {
+            if source.fields.iter().any(|f| f == name) {
+                return Err(RedisError::Str("field is used by the stream source"));
+            }
+        }
+        self.fields.remove(index);
+        if index < self.defaults.len() {
+            self.defaults.remove(index);
+        }
+        for (_, row) in &mut self.rows {
+            row.remove(index);
+        }
+        for view in &mut self.views {
+            if view.from.is_none() {
+                for agg in &mut view.fields {
+                    if agg.index > index {
+                        agg.index -= 1;
+                    }
+                }
+            }
+        }
+        self.index_fields();
+        REDIS_OK
+    }
+
+    /// Structured description of the table, the views and their states.
+    pub fn info(&self) -> RedisValue {
+        let string = |s: &str| RedisValue::SimpleString(s.to_owned());
+        let integer = |n: u64| RedisValue::Integer(n as i64);
+        let optional = |n: Option<u64>| n.map_or(RedisValue::Null, integer);
+        let mut views = Vec::new();
+        for view in &self.views {
+            let source = view
+                .from
+                .as_ref()
+                .and_then(|from| self.views.iter().find(|v| &v.name == from));
+            let fields = view
+                .fields
+                .iter()
+                .map(|agg| {
+                    let field = match source {
+                        None => &self.fields[agg.index],
+                        Some(source) => &source.fields[agg.index].name,
+                    };
+                    RedisValue::Array(vec![
+                        string(&agg.name),
+                        string(agg.op.name()),
+                        string(field),
+                    ])
+                })
+                .collect();
+            let output = match (&view.groupby, &view.output, &view.format) {
+                (_, _, Format::Hash) => "hash",
+                (None, _, _) => "string",
+                (Some(_), Output::Hash, _) => "hash",
+                (Some(_), Output::Zset, _) => "zset",
+            };
+            let format = match view.format {
+                Format::Array => "array",
+                Format::Object => "object",
+                Format::Hash => "hash",
+            };
+            views.push(RedisValue::Array(vec![
+                string("name"),
+                string(&view.name),
+                string("interval"),
+                optional(view.interval().map(u64::from)),
+                string("from"),
+                view.from.as_deref().map_or(RedisValue::Null, string),
+                string("fields"),
+                RedisValue::Array(fields),
+                string("output"),
+                string(output),
+                string("format"),
+                string(format),
+                string("stream"),
+                view.stream
+                    .as_ref()
+                    .map_or(RedisValue::Null, |s| string(&s.stream)),
+                string("states"),
+                view.states.as_ref().map_or(RedisValue::Null, |s| string(s)),
+                string("retention"),
+                optional(view.retention),
+                string("maxbuckets"),
+                optional(view.maxbuckets),
+                string("nosave"),
+                integer(view.nosave as u64),
+                string("current"),
+                view.groupby
+                    .as_ref()
+                    .map_or(RedisValue::Null, |g| RedisValue::Float(g.current)),
+                string("rows"),
+                integer(view.stats.rows),
+                string("late"),
+                integer(view.stats.late),
+                string("last_save"),
+                integer(view.stats.last_save),
+            ]));
+        }
+        let columns = self
+            .fields
+            .iter()
+            .enumerate()
+            .map(|(i, field)| {
+                RedisValue::Array(vec![
+                    string(field),
+                    self.defaults
+                        .get(i)
+                        .cloned()
+                        .flatten()
+                        .map_or(RedisValue::Null, RedisValue::Float),
+                ])
+            })
+            .collect();
+        RedisValue::Array(vec![
+            string("columns"),
+            RedisValue::Array(columns),
+            string("last_id"),
+            RedisValue::SimpleString(self.last_id.clone().into()),
+            string("source"),
+            self.source.as_ref().map_or(RedisValue::Null, |source| {
+                RedisValue::Array(vec![
+                    string(&source.stream),
+                    RedisValue::Array(source.fields.iter().map(|f| string(f)).collect()),
+                    RedisValue::SimpleString(source.last_id.clone().into()),
+                ])
+            }),
+            string("retain"),
+            optional(self.retain),
+            string("save_every"),
+            integer(self.save_interval().as_millis() as u64),
+            string("retained_rows"),
+            integer(self.rows.len() as u64),
+            string("views"),
+            RedisValue::Array(views),
+        ])
+    }
+
+    fn view_index(&self, name: &str) -> Result<usize, RedisError> {
+        self.views
             .iter()
-            .map(|s| parse_float(&s))
-            .collect::<Result<Vec<_>, _>>()?;
-        args.insert(0, id.ms as Value / 1000.);
+            .position(|v| v.name == name)
+            .ok_or(RedisError::Str("view not exist"))
+    }
+
+    /// Remove a view, the source views of rollup views can't be dropped.
+    pub fn drop_view(
+        &mut self,
+        ctx: &ModuleContext,
+        key: &str,
+        name: &str,
+        delete_output: bool,
+    ) -> RedisResult {
+        let i = self.view_index(name)?;
+        if let Some(rollup) = self.views.iter().find(|v| v.from.as_deref() == Some(name)) {
+            return Err(RedisError::String(format!(
+                "view is the source of rollup view {}",
+                rollup.name
+            )));
+        }
+        if delete_output {
+            self.views[i].check_outputs(key)?;
+            self.views[i].delete_outputs(ctx)?;
+        }
+        self.views.remove(i);
+        REDIS_OK
+    }
+
+    /// Rename a view and its output key, the rollup views follow the new name.
+    pub fn rename_view(
+        &mut self,
+        ctx: &ModuleContext,
+        key: &str,
+        name: &str,
+        new_name: &str,
+    ) -> RedisResult {
+        let i = self.view_index(name)?;
+        if self.views.iter().any(|v| v.name == new_name) {
+            return Err(RedisError::Str("view already exist"));
+        }
+        if name == key || new_name == key {
+            return Err(RedisError::Str("view name can't be the table key"));
+        }
+        // the per-bucket keys are named after the view, they would be left behind
+        if self.views[i].groupby.is_some() && self.views[i].format == Format::Hash {
+            return Err(RedisError::Str("can't rename the view with FORMAT HASH"));
+        }
+        // the output key of the new name belongs to someone else even if the view has no output
+        if let RedisValue::Integer(1) = ctx.call("exists", &[new_name])? {
+            return Err(RedisError::Str("output key already exist"));
+        }
+        if let RedisValue::Integer(1) = ctx.call("exists", &[name])? {
+            if let RedisValue::Integer(0) = ctx.call("renamenx", &[name, new_name])? {
+                return Err(RedisError::Str("output key already exist"));
+            }
+        }
+        self.views[i].name = new_name.to_owned();
         for view in &mut self.views {
-            view.update(ctx, &args)?;
+            if view.from.as_deref() == Some(name) {
+                view.from = Some(new_name.to_owned());
+            }
+        }
+        REDIS_OK
+    }
+
+    /// The buckets of a view in the time range, `-` and `+` are the unbounded ends.
+    pub fn range(&self, ctx: &ModuleContext, name: &str, args: &[String]) -> RedisResult {
+        let view = &self.views[self.view_index(name)?];
+        if args.len() < 2 {
+            return Err(RedisError::WrongArity);
+        }
+        let bound = |arg: &str, unbounded: &str, default: Time| {
+            if arg == unbounded {
+                Ok(default)
+            } else {
+                parse_float(arg)
+            }
+        };
+        let from = bound(&args[0], "-", Time::NEG_INFINITY)?;
+        let to = bound(&args[1], "+", Time::INFINITY)?;
+        let mut count = usize::MAX;
+        let mut rev = false;
+        let mut args = &args[2..];
+        while !args.is_empty() {
+            match args[0].to_lowercase().as_str() {
+                "count" if args.len() > 1 => {
+                    let n = parse_integer(&args[1])?;
+                    if n < 0 {
+                        return Err(RedisError::Str("count must be positive"));
+                    }
+                    count = n as usize;
+                    args = &args[2..];
+                }
+                "rev" => {
+                    rev = true;
+                    args = &args[1..];
+                }
+                _ => return Err(RedisError::Str("syntax error")),
+            }
+        }
+        Ok(reply_buckets(view.range(ctx, from, to, rev, count)?))
+    }
+
+    /// Re-aggregate the buckets of a view into a coarser interval:
+    /// `INTERVAL n [FROM time] [TO time]`.
+    pub fn query(&self, ctx: &ModuleContext, name: &str, args: &[String]) -> RedisResult {
+        let view = &self.views[self.view_index(name)?];
+        let mut interval = None;
+        let mut from = Time::NEG_INFINITY;
+        let mut to = Time::INFINITY;
+        for chunk in args.chunks(2) {
+            if chunk.len() != 2 {
+                return Err(RedisError::WrongArity);
+            }
+            match chunk[0].to_lowercase().as_str() {
+                "interval" => {
+                    let n = parse_integer(&chunk[1])?
+                        .try_into()
+                        .map_err(|err: TryFromIntError| RedisError::String(err.to_string()))?;
+                    interval = Some(n);
+                }
+                "from" => from = parse_float(&chunk[1])?,
+                "to" => to = parse_float(&chunk[1])?,
+                _ => return Err(RedisError::Str("syntax error")),
+            }
+        }
+        let interval = interval.ok_or(RedisError::Str("INTERVAL is required"))?;
+        Ok(reply_buckets(view.query(ctx, interval, from, to)?))
+    }
+
+    /// The view fields of a sql statement in the arguments of `agg.view`.
+    fn sql_fields(&self, query: &SqlQuery) -> Result<Vec<String>, RedisError> {
+        if let Some(column) = query.time_columns.iter().find(|c| **c != self.fields[0]) {
+            return Err(RedisError::String(format!(
+                "{} is not the time column",
+                column
+            )));
+        }
+        let mut args = Vec::new();
+        for (func, field, alias) in &query.fields {
+            args.push(func.clone());
+            args.push(field.clone().unwrap_or_else(|| self.fields[0].clone()));
+            if let Some(alias) = alias {
+                args.push("as".to_owned());
+                args.push(alias.clone());
+            }
+        }
+        Ok(args)
+    }
+
+    /// Create a view by a `CREATE VIEW` statement.
+    fn sql_view(&mut self, key: &str, name: &str, query: &SqlQuery) -> RedisResult {
+        let mut options = vec![name.to_owned()];
+        if let Some(n) = query.interval {
+            options.push("interval".to_owned());
+            options.push(n.to_string());
+        }
+        options.extend(self.sql_fields(query)?);
+        self.add_view(key, &options)
+    }
+
+    /// Run a `SELECT` statement once over the retained rows.
+    fn sql(&self, key: &str, query: &SqlQuery) -> RedisResult {
+        let args = self.sql_fields(query)?;
+        if self.retain.is_none() {
+            return Err(RedisError::Str("raw rows are not retained"));
+        }
+        let view = self.parse_view(key, String::new(), query.interval, &args)?;
+        let func = TimeFunc::Interval(query.interval.unwrap_or(0));
+        let mut buckets: BTreeMap<i64, Vec<Box<dyn AggOp>>> = BTreeMap::new();
+        for (id, row) in &self.rows {
+            if id.ms < query.from || id.ms > query.to {
+                continue;
+            }
+            let time = match query.interval {
+                Some(_) => func.apply(id.ms as Time / 1000.) as i64,
+                None => 0,
+            };
+            let ops = buckets.entry(time).or_insert_with(|| {
+                view.fields
+                    .iter()
+                    .map(|agg| parse_agg_type(agg.op.name()).unwrap())
+                    .collect()
+            });
+            for (agg, op) in view.fields.iter().zip(ops) {
+                if let Some(value) = row[agg.index] {
+                    op.update(value);
+                }
+            }
+        }
+        let mut buckets = buckets
+            .into_iter()
+            .map(|(time, ops)| (time as Time, ops.iter().map(|op| op.current()).collect()))
+            .collect::<Vec<(Time, Vec<Option<Value>>)>>();
+        if query.interval.is_some() {
+            return Ok(reply_buckets(buckets));
+        }
+        // a single row over all the rows
+        let values = buckets.pop().map_or_else(
+            || view.fields.iter().map(|agg| agg.op.current()).collect(),
+            |b| b.1,
+        );
+        Ok(RedisValue::Array(
+            values
+                .into_iter()
+                .map(|v| v.map_or(RedisValue::Null, RedisValue::Float))
+                .collect(),
+        ))
+    }
+
+    /// Clear the partial states of a view, keep the definition.
+    pub fn reset_view(&mut self, name: &str) -> RedisResult {
+        let i = self.view_index(name)?;
+        let view = &mut self.views[i];
+        for agg in &mut view.fields {
+            agg.op.reset();
+        }
+        if let Some(ref mut groupby) = view.groupby {
+            groupby.current = 0.;
+        }
+        REDIS_OK
+    }
+
+    /// Replay the retained rows through a fresh copy of the view, and replace the view.
+    ///
+    /// The output key is deleted first if replaying all the rows, otherwise the buckets since
+    /// `from` (in milliseconds) are overwritten.
+    pub fn rebuild_view(
+        &mut self,
+        ctx: &ModuleContext,
+        key: &str,
+        name: &str,
+        from: Option<u64>,
+    ) -> RedisResult {
+        if self.retain.is_none() {
+            return Err(RedisError::Str("raw rows are not retained"));
+        }
+        let i = self.view_index(name)?;
+        if self.views[i].from.is_some() {
+            return Err(RedisError::Str("can't rebuild rollup view"));
+        }
+        // the rollup views depending on the view are rebuilt too, they are always after the source
+        let mut rebuilt = vec![i];
+        for j in i + 1..self.views.len() {
+            if let Some(ref source) = self.views[j].from {
+                if rebuilt.iter().any(|&k| &self.views[k].name == source) {
+                    rebuilt.push(j);
+                }
+            }
+        }
+        // align to the largest interval, the rollup buckets are merged from the start
+        let from = from.map(|from| {
+            let interval = rebuilt
+                .iter()
+                .filter_map(|&j| self.views[j].groupby.as_ref())
+                .map(|groupby| {
+                    let TimeFunc::Interval(n) = groupby.func;
+                    n as u64 * 1000
+                })
+                .filter(|&n| n > 0)
+                .max()
+                .unwrap_or(1);
+            from / interval * interval
+        });
+        if from.is_none() {
+            for &j in &rebuilt {
+                self.views[j].check_outputs(key)?;
+            }
+        }
+        let mut streams = Vec::with_capacity(rebuilt.len());
+        for &j in &rebuilt {
+            let mut view = self.views[j].fresh();
+            if from.is_none() {
+                view.delete_outputs(ctx)?;
+            }
+            // the buckets are already emitted to the stream
+            streams.push(view.stream.take());
+            self.views[j] = view;
+        }
+        let rows = mem::take(&mut self.rows);
+        let from = from.unwrap_or(0);
+        let result = rows
+            .iter()
+            .filter(|(id, _)| id.ms >= from)
+            .try_for_each(|(id, row)| {
+                let time = id.ms as Value / 1000.;
+                self.update_view(ctx, None, i, time, Input::Row(row))
+                    .map(|_| ())
+            });
+        self.rows = rows;
+        result?;
+        for (&j, stream) in rebuilt.iter().zip(streams) {
+            self.views[j].save(ctx)?;
+            self.views[j].stream = stream;
+        }
+        REDIS_OK
+    }
+
+    /// Replace the partial states with the merge of the partial states of the source tables.
+    ///
+    /// For group by views, the sources are merged into the latest bucket, the sources which are
+    /// still in older buckets are ignored, the previous bucket of the table is finalized like a
+    /// row starting a new bucket does if it's older. First and last are merged by the time of
+    /// the values.
+    pub fn merge_tables(
+        &mut self,
+        ctx: &ModuleContext,
+        key: &str,
+        sources: &[&AggTable],
+    ) -> RedisResult {
+        for source in sources {
+            if source.fields != self.fields
+                || source.views.len() != self.views.len()
+                || !source
+                    .views
+                    .iter()
+                    .zip(&self.views)
+                    .all(|(a, b)| a.same_schema(b))
+            {
+                return Err(RedisError::Str("tables have different schema"));
+            }
+        }
+        for i in 0..self.views.len() {
+            let mut target = None;
+            if let Some(ref groupby) = self.views[i].groupby {
+                let current = sources
+                    .iter()
+                    .map(|t| t.views[i].groupby.as_ref().unwrap().current)
+                    .fold(groupby.current, Time::max);
+                // the older bucket of dest is published and merged into the rollup views, which
+                // are merged after, then it's saved and reset
+                self.rollover(ctx, Some(key), i, current)?;
+                self.views[i].advance(ctx, current)?;
+                target = Some(current);
+            }
+            let view = &mut self.views[i];
+            for source in sources {
+                let source = &source.views[i];
+                if source.groupby.as_ref().map(|g| g.current) != target {
+                    continue;
+                }
+                for (agg, other) in view.fields.iter_mut().zip(&source.fields) {
+                    agg.op.merge(other.op.as_ref());
+                }
+            }
+        }
+        for source in sources {
+            if source.last_id > self.last_id {
+                self.last_id = source.last_id.clone();
+            }
+        }
+        REDIS_OK
+    }
+
+    /// Export the raw partial states of the views as json.
+    pub fn export_state(&self) -> Result<String, RedisError> {
+        let states = self
+            .views
+            .iter()
+            .map(|view| {
+                (
+                    view.name.clone(),
+                    view.groupby.as_ref().map(|g| g.current),
+                    view.fields.iter().map(|agg| &agg.op).collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<ViewState<_>>>();
+        serde_json::to_string(&states).map_err(|err| RedisError::String(err.to_string()))
+    }
+
+    /// Replace the partial states of the views with the exported ones, the views are matched by
+    /// position, so the state can be imported into another table with the same views.
+    pub fn import_state(&mut self, buf: &str) -> RedisResult {
+        let states = serde_json::from_str::<Vec<ViewState<Box<dyn AggOp>>>>(buf)
+            .map_err(|err| RedisError::String(err.to_string()))?;
+        // validate all the states before changing anything
+        if states.len() != self.views.len()
+            || !self
+                .views
+                .iter()
+                .zip(&states)
+                .all(|(view, (_, current, ops))| {
+                    view.groupby.is_some() == current.is_some()
+                        && view.fields.len() == ops.len()
+                        && view
+                            .fields
+                            .iter()
+                            .zip(ops)
+                            .all(|(agg, op)| agg.op.name() == op.name())
+                })
+        {
+            return Err(RedisError::Str("state doesn't match the views"));
+        }
+        let previous = self.swap_states(states);
+        if let Err(err) = self.validate() {
+            self.swap_states(previous);
+            return Err(err);
+        }
+        REDIS_OK
+    }
+
+    /// Replace the current buckets and the op states of the views, returns the replaced ones.
+    fn swap_states(
+        &mut self,
+        states: Vec<ViewState<Box<dyn AggOp>>>,
+    ) -> Vec<ViewState<Box<dyn AggOp>>> {
+        self.views
+            .iter_mut()
+            .zip(states)
+            .map(|(view, (name, current, ops))| {
+                let current = match (view.groupby.as_mut(), current) {
+                    (Some(groupby), Some(current)) => {
+                        Some(mem::replace(&mut groupby.current, current))
+                    }
+                    _ => None,
+                };
+                let ops = view
+                    .fields
+                    .iter_mut()
+                    .zip(ops)
+                    .map(|(agg, op)| mem::replace(&mut agg.op, op))
+                    .collect();
+                (name, current, ops)
+            })
+            .collect()
+    }
+
+    pub fn save(&mut self, ctx: &ModuleContext, key: &str) -> Result<(), RedisError> {
+        for view in &mut self.views {
+            view.save(ctx)?;
+            view.publish(ctx, key);
+        }
+        Ok(())
+    }
+
+    /// The periodic save, the views with `NOSAVE` are skipped.
+    fn save_periodic(&mut self, ctx: &ModuleContext, key: &str) -> Result<(), RedisError> {
+        for view in self.views.iter_mut().filter(|view| !view.nosave) {
+            view.save(ctx)?;
+            view.publish(ctx, key);
+        }
+        Ok(())
+    }
+}
+
+//////////////////////////////////////////////////////
+
+/// Log with the io, there's no context while loading.
+unsafe fn log_io_error(rdb: *mut raw::RedisModuleIO, message: &str) {
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    raw::RedisModule_LogIOError.unwrap()(
+        rdb,
+        b"warning\0".as_ptr() as *const c_char,
+        b"%s\0".as_ptr() as *const c_char,
+        message.as_ptr(),
+    );
+}
+
+/// Returns NULL if the payload is corrupted or invalid. Redis then aborts the load: the server
+/// exits when it loads the rdb at startup or by `DEBUG RELOAD`, and `RESTORE` replies with an
+/// error instead (since redis 6.2), so nothing is half loaded.
+#[allow(non_snake_case, unused)]
+unsafe extern "C" fn agg_rdb_load(rdb: *mut raw::RedisModuleIO, encver: c_int) -> *mut c_void {
+    let mut reader = RdbReader(RdbInput::Io(rdb), encver);
+    let result = match encver {
+        // json before the typed encoding
+        1 => reader.string().and_then(|buf| {
+            serde_json::from_str::<AggTable>(&buf)
+                .map_err(|err| RedisError::String(err.to_string()))
+        }),
+        2..=ENCVER => AggTable::load_rdb(&mut reader),
+        _ => Err(RedisError::String(format!("unknown encver {}", encver))),
+    };
+    let mut table = match result.and_then(|table| table.validate().map(|_| table)) {
+        Ok(table) => table,
+        Err(err) => {
+            log_io_error(rdb, &format!("load aggregation table failed: {:?}", err));
+            return ptr::null_mut();
+        }
+    };
+    table.restore();
+    // the older encodings don't have the name, and the key may be renamed meanwhile
+    let name = raw::RedisModule_GetKeyNameFromIO.unwrap()(rdb);
+    if !name.is_null() {
+        table.name = module_string(name as *mut raw::RedisModuleString);
+    }
+    // the source is subscribed and the periodic save starts after the db is found
+    let table = Box::into_raw(Box::new(table));
+    register_table(&mut *table);
+    table as *mut c_void
+}
+
+/// Rebuild the table in `agg.restore`, the state is the same as rdb.
+#[allow(non_snake_case, unused)]
+#[no_mangle]
+unsafe extern "C" fn agg_aof_rewrite(
+    aof: *mut raw::RedisModuleIO,
+    key: *mut raw::RedisModuleString,
+    value: *mut c_void,
+) {
+    let table: &AggTable = mem::transmute(value);
+    // the typed encoding keeps the non-finite values, which are null in json
+    let mut writer = RdbWriter::Buffer(Vec::new());
+    writer.unsigned(ENCVER as u64);
+    table.save_rdb(&mut writer);
+    let state = match writer {
+        RdbWriter::Buffer(buf) => hex_encode(&buf),
+        RdbWriter::Io(_) => unreachable!(),
+    };
+    raw::RedisModule_EmitAOF.unwrap()(
+        aof,
+        b"agg.restore\0".as_ptr() as *const c_char,
+        b"sb\0".as_ptr() as *const c_char,
+        key,
+        state.as_ptr() as *const c_char,
+        state.len(),
+    );
+}
+
+#[allow(non_snake_case, unused)]
+#[no_mangle]
+unsafe extern "C" fn agg_free(value: *mut c_void) {
+    let table = Box::from_raw(value as *mut AggTable);
+    // the periodic save stops once the table is unregistered
+    unregister_table(&table);
+    if let (Some(db), Some(source)) = (table.db, &table.source) {
+        unregister_source(db, &source.stream, &table.name);
+    }
+}
+
+#[allow(non_snake_case, unused)]
+#[no_mangle]
+unsafe extern "C" fn agg_rdb_save(rdb: *mut raw::RedisModuleIO, value: *mut c_void) {
+    let table: &AggTable = mem::transmute(value);
+    table.save_rdb(&mut RdbWriter::Io(rdb));
+}
+
+pub(crate) static AGG_REDIS_TYPE: RedisType = RedisType::new(
+    "aggre-hy1",
+    ENCVER,
+    raw::RedisModuleTypeMethods {
+        version: raw::REDISMODULE_TYPE_METHOD_VERSION as u64,
+
+        rdb_load: Some(agg_rdb_load),
+        rdb_save: Some(agg_rdb_save),
+        aof_rewrite: Some(agg_aof_rewrite),
+        free: Some(agg_free),
+
+        aux_load: None,
+        aux_save: None,
+        aux_save_triggers: 0,
+
+        // Currently unused by Redis
+        mem_usage: None,
+        digest: None,
+    },
+);
+
+/// Reply the buckets as `[[time, [value, ...]], ...]`.
+fn reply_buckets(buckets: Vec<(Time, Vec<Option<Value>>)>) -> RedisValue {
+    RedisValue::Array(
+        buckets
+            .into_iter()
+            .map(|(time, values)| {
+                RedisValue::Array(vec![
+                    RedisValue::Float(time),
+                    RedisValue::Array(
+                        values
+                            .into_iter()
+                            .map(|v| v.map_or(RedisValue::Null, RedisValue::Float))
+                            .collect(),
+                    ),
+                ])
+            })
+            .collect(),
+    )
+}
+
+/// A registered table, the module level callbacks open the table by the key name in the db, and
+/// check the serial number, instead of keeping the address.
+struct TableEntry {
+    /// unknown for the tables loaded from rdb until the loading ends.
+    db: Option<c_int>,
+    name: String,
+    /// the interval of the periodic save.
+    interval: Duration,
+    /// when the next periodic save is due.
+    due: Instant,
+}
+
+/// All the tables by the serial numbers, maintained by `agg.new`, rdb load and `agg_free`.
+static TABLES: Mutex<BTreeMap<u64, TableEntry>> = Mutex::new(BTreeMap::new());
+/// The serial numbers are never reused, so a freed table can't be mistaken for a new table with
+/// the same key name.
+static NEXT_SERIAL: AtomicU64 = AtomicU64::new(1);
+/// The timer of the periodic saves and when it fires, it's armed for the earliest due save.
+static TICK: Mutex<Option<(raw::RedisModuleTimerID, Instant)>> = Mutex::new(None);
+
+fn register_table(table: &mut AggTable) {
+    table.serial = NEXT_SERIAL.fetch_add(1, Ordering::Relaxed);
+    let interval = table.save_interval();
+    let entry = TableEntry {
+        db: table.db,
+        name: table.name.clone(),
+        interval,
+        due: Instant::now() + interval,
+    };
+    TABLES.lock().unwrap().insert(table.serial, entry);
+}
+
+fn unregister_table(table: &AggTable) {
+    TABLES.lock().unwrap().remove(&table.serial);
+}
+
+/// Keep the db and the key name of the registered table in sync.
+fn update_entry(table: &AggTable) {
+    if let Some(entry) = TABLES.lock().unwrap().get_mut(&table.serial) {
+        entry.db = table.db;
+        entry.name = table.name.clone();
+    }
+}
+
+/// Escape the special characters of the glob-style patterns of redis.
+fn glob_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Glob-style pattern matching, supports `*`, `?` and `\` escape.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => (0..=s.len()).any(|i| glob_match(rest, &s[i..])),
+        Some((b'?', rest)) => !s.is_empty() && glob_match(rest, &s[1..]),
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            s.first() == Some(&rest[0]) && glob_match(&rest[1..], &s[1..])
+        }
+        Some((c, rest)) => s.first() == Some(c) && glob_match(rest, &s[1..]),
+    }
+}
+
+/// Tables subscribed to each stream by the db and the stream, maintained by `agg.source`, the
+/// end of loading and `agg_free`.
+static STREAM_SOURCES: Mutex<BTreeMap<(c_int, String), BTreeSet<String>>> =
+    Mutex::new(BTreeMap::new());
+
+fn register_source(db: c_int, stream: &str, table: &str) {
+    let mut sources = STREAM_SOURCES.lock().unwrap();
+    sources
+        .entry((db, stream.to_owned()))
+        .or_insert_with(BTreeSet::new)
+        .insert(table.to_owned());
+}
+
+fn unregister_source(db: c_int, stream: &str, table: &str) {
+    let mut sources = STREAM_SOURCES.lock().unwrap();
+    let key = (db, stream.to_owned());
+    if let Some(tables) = sources.get_mut(&key) {
+        tables.remove(table);
+        if tables.is_empty() {
+            sources.remove(&key);
+        }
+    }
+}
+
+fn reply_string(value: &RedisValue) -> Result<&str, RedisError> {
+    match value {
+        RedisValue::SimpleString(s) | RedisValue::BulkString(s) => Ok(s),
+        _ => Err(RedisError::Str("expect string reply")),
+    }
+}
+
+unsafe fn module_string(s: *mut raw::RedisModuleString) -> String {
+    let mut len = 0;
+    let ptr = raw::RedisModule_StringPtrLen.unwrap()(s, &mut len);
+    String::from_utf8_lossy(slice::from_raw_parts(ptr as *const u8, len)).into_owned()
+}
+
+/// `Context` with the raw pointer, which is private in redis-module 0.10, for the module apis
+/// `Context` doesn't wrap.
+pub struct ModuleContext {
+    ctx: Context,
+    raw: *mut raw::RedisModuleCtx,
+}
+
+impl Deref for ModuleContext {
+    type Target = Context;
+    fn deref(&self) -> &Context {
+        &self.ctx
+    }
+}
+
+impl ModuleContext {
+    fn new(raw: *mut raw::RedisModuleCtx) -> ModuleContext {
+        ModuleContext {
+            ctx: Context::new(raw),
+            raw,
+        }
+    }
+
+    fn selected_db(&self) -> c_int {
+        unsafe { raw::RedisModule_GetSelectedDb.unwrap()(self.raw) }
+    }
+
+    fn select_db(&self, db: c_int) {
+        unsafe { raw::RedisModule_SelectDb.unwrap()(self.raw, db) };
+    }
+
+    /// The number of the dbs, by `CONFIG GET databases`.
+    fn databases(&self) -> c_int {
+        let count = match self.call("config", &["get", "databases"]) {
+            Ok(RedisValue::Array(values)) if values.len() == 2 => reply_string(&values[1])
+                .ok()
+                .and_then(|count| count.parse().ok()),
+            _ => None,
+        };
+        count.unwrap_or(16)
+    }
+
+    fn is_replica(&self) -> bool {
+        let flags = unsafe { raw::RedisModule_GetContextFlags.unwrap()(self.raw) };
+        flags as u32 & raw::REDISMODULE_CTX_FLAGS_SLAVE != 0
+    }
+
+    /// Fire a generic keyspace event, the key string is freed by the auto memory of the context.
+    fn notify_keyspace_event(&self, event: &str, key: &str) {
+        let event = CString::new(event).unwrap();
+        unsafe {
+            let key = raw::RedisModule_CreateString.unwrap()(
+                self.raw,
+                key.as_ptr() as *const c_char,
+                key.len(),
+            );
+            raw::RedisModule_NotifyKeyspaceEvent.unwrap()(
+                self.raw,
+                raw::REDISMODULE_NOTIFY_GENERIC as c_int,
+                event.as_ptr(),
+                key,
+            );
+        }
+    }
+
+    fn stop_timer(&self, timer: raw::RedisModuleTimerID) {
+        unsafe { raw::RedisModule_StopTimer.unwrap()(self.raw, timer, ptr::null_mut()) };
+    }
+
+    /// Create a timer with a raw callback, which gets the raw context, the data is passed as is.
+    fn create_timer(
+        &self,
+        period: Duration,
+        callback: unsafe extern "C" fn(*mut raw::RedisModuleCtx, *mut c_void),
+        data: u64,
+    ) -> raw::RedisModuleTimerID {
+        unsafe {
+            raw::RedisModule_CreateTimer.unwrap()(
+                self.raw,
+                period.as_millis() as raw::mstime_t,
+                Some(callback),
+                data as usize as *mut c_void,
+            )
+        }
+    }
+}
+
+fn consume_stream(ctx: &ModuleContext, stream: &str) {
+    // the replicas get the rows from the master
+    if ctx.is_replica() {
+        return;
+    }
+    let db = ctx.selected_db();
+    let tables = match STREAM_SOURCES.lock().unwrap().get(&(db, stream.to_owned())) {
+        None => return,
+        Some(tables) => tables.clone(),
+    };
+    for name in tables {
+        let key = ctx.open_key_writable(&name);
+        match key.get_value::<AggTable>(&AGG_REDIS_TYPE) {
+            Ok(Some(table)) => {
+                if let Err(err) = table.consume_source(ctx, &name, true) {
+                    ctx.log(
+                        LogLevel::Warning,
+                        &format!("consume stream {} failed: {:?}", stream, err),
+                    );
+                }
+            }
+            _ => unregister_source(db, stream, &name),
+        }
+    }
+}
+
+/// Keep the name and the db of the table in sync when the key is renamed, moved or restored.
+fn sync_table(ctx: &ModuleContext, name: &str) {
+    let key = ctx.open_key_writable(name);
+    if let Ok(Some(table)) = key.get_value::<AggTable>(&AGG_REDIS_TYPE) {
+        if let (Some(db), Some(source)) = (table.db, &table.source) {
+            unregister_source(db, &source.stream, &table.name);
+        }
+        table.name = name.to_owned();
+        // the db of the context is the db of the key, which is changed by `MOVE` and `RESTORE`
+        let db = ctx.selected_db();
+        table.db = Some(db);
+        if let Some(ref source) = table.source {
+            register_source(db, &source.stream, name);
+        }
+        update_entry(table);
+    }
+    schedule(ctx);
+}
+
+/// Find the dbs of the tables loaded from rdb when the loading ends, then subscribe their sources.
+unsafe extern "C" fn on_loading(
+    ctx: *mut raw::RedisModuleCtx,
+    _event: raw::RedisModuleEvent,
+    subevent: u64,
+    _data: *mut c_void,
+) {
+    if subevent != raw::REDISMODULE_SUBEVENT_LOADING_ENDED as u64 {
+        return;
+    }
+    let ctx = ModuleContext::new(ctx);
+    ctx.auto_memory();
+    let loaded = TABLES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, entry)| entry.db.is_none())
+        .map(|(&serial, entry)| (serial, entry.name.clone()))
+        .collect::<Vec<_>>();
+    let selected = ctx.selected_db();
+    let databases = ctx.databases();
+    for (serial, name) in loaded {
+        for db in 0..databases {
+            ctx.select_db(db);
+            let key = ctx.open_key_writable(&name);
+            if let Ok(Some(table)) = key.get_value::<AggTable>(&AGG_REDIS_TYPE) {
+                if table.serial == serial {
+                    table.set_db(db);
+                    break;
+                }
+            }
+        }
+    }
+    ctx.select_db(selected);
+    schedule(&ctx);
+}
+
+unsafe extern "C" fn on_keyspace_event(
+    ctx: *mut raw::RedisModuleCtx,
+    _type: c_int,
+    event: *const c_char,
+    key: *mut raw::RedisModuleString,
+) -> c_int {
+    let ctx = ModuleContext::new(ctx);
+    let key = module_string(key);
+    match CStr::from_ptr(event).to_bytes() {
+        b"xadd" => consume_stream(&ctx, &key),
+        b"rename_to" | b"move_to" | b"restore" => sync_table(&ctx, &key),
+        _ => {}
+    }
+    raw::REDISMODULE_OK as c_int
+}
+
+/// Arm the timer for the earliest due periodic save, unless it fires earlier already.
+fn schedule(ctx: &ModuleContext) {
+    let due = TABLES.lock().unwrap().values().map(|entry| entry.due).min();
+    let due = match due {
+        Some(due) => due,
+        None => return,
+    };
+    let mut tick = TICK.lock().unwrap();
+    if let Some((timer, at)) = *tick {
+        if at <= due {
+            return;
+        }
+        ctx.stop_timer(timer);
+    }
+    let period = due.saturating_duration_since(Instant::now());
+    *tick = Some((ctx.create_timer(period, on_tick, 0), due));
+}
+
+/// Save the tables which are due, the tables are opened by the key names, so a table freed
+/// meanwhile is never touched.
+unsafe extern "C" fn on_tick(ctx: *mut raw::RedisModuleCtx, _data: *mut c_void) {
+    let ctx = ModuleContext::new(ctx);
+    ctx.auto_memory();
+    *TICK.lock().unwrap() = None;
+    let now = Instant::now();
+    let mut due = Vec::new();
+    for (&serial, entry) in TABLES.lock().unwrap().iter_mut() {
+        match entry.db {
+            Some(db) if entry.due <= now => {
+                entry.due = now + entry.interval;
+                due.push((serial, db, entry.name.clone()));
+            }
+            _ => {}
+        }
+    }
+    // the replicas get the outputs from the master
+    if !ctx.is_replica() {
+        let selected = ctx.selected_db();
+        for (serial, db, name) in due {
+            ctx.select_db(db);
+            let key = ctx.open_key_writable(&name);
+            match key.get_value::<AggTable>(&AGG_REDIS_TYPE) {
+                Ok(Some(table)) if table.serial == serial => {
+                    if let Err(err) = table.save_periodic(&ctx, &name) {
+                        ctx.log(
+                            LogLevel::Warning,
+                            &format!("save {} failed: {:?}", name, err),
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+        ctx.select_db(selected);
+    }
+    schedule(&ctx);
+}
+
+fn new_table(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() <= 2 {
+        return Err(RedisError::WrongArity);
+    }
+    ctx.auto_memory();
+    let key = ctx.open_key_writable(&args[1]);
+    match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+        Some(_) => {
+            return Err(RedisError::Str("key already exist"));
+        }
+        None => {
+            let (fields, defaults, options) = AggTable::parse_columns(&args[2..])?;
+            if fields.is_empty() {
+                return Err(RedisError::WrongArity);
+            }
+            let mut table = AggTable::new(args[1].clone(), fields, defaults);
+            table.set_options(options)?;
+            table.set_db(ctx.selected_db());
+            key.set_value(&AGG_REDIS_TYPE, table)?;
+            let table = key
+                .get_value::<AggTable>(&AGG_REDIS_TYPE)?
+                .ok_or(RedisError::Str("impossible"))?;
+            register_table(table);
+            schedule(ctx);
+        }
+    }
+    ctx.replicate_verbatim();
+    REDIS_OK
+}
+
+/// Create a table from the serialized state, emitted by the aof rewrite.
+fn hex_encode(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        s.push(DIGITS[(byte >> 4) as usize] as char);
+        s.push(DIGITS[(byte & 0xf) as usize] as char);
+    }
+    s
+}
+
+fn hex_decode(s: &str) -> Result<Vec<u8>, RedisError> {
+    if !s.is_ascii() || s.len() % 2 != 0 {
+        return Err(RedisError::Str("invalid hex"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+        .collect::<Result<_, _>>()
+        .map_err(|_| RedisError::Str("invalid hex"))
+}
+
+/// Parse the state of `agg.restore`, the typed encoding in hex prefixed with the encver, or json
+/// which is written by the older versions.
+fn parse_state(state: &str) -> Result<AggTable, RedisError> {
+    if state.starts_with('{') {
+        return serde_json::from_str::<AggTable>(state)
+            .map_err(|err| RedisError::String(format!("invalid state: {}", err)));
+    }
+    let mut reader = RdbReader(RdbInput::Buffer(hex_decode(state)?, 0), 0);
+    reader.1 = match reader.unsigned()? {
+        n if (2..=ENCVER as u64).contains(&n) => n as c_int,
+        n => return Err(RedisError::String(format!("unknown encver {}", n))),
+    };
+    let table = AggTable::load_rdb(&mut reader)?;
+    if !reader.is_end() {
+        return Err(RedisError::Str("invalid state: trailing bytes"));
+    }
+    Ok(table)
+}
+
+fn restore_table(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() != 3 {
+        return Err(RedisError::WrongArity);
+    }
+    ctx.auto_memory();
+    let key = ctx.open_key_writable(&args[1]);
+    if key.get_value::<AggTable>(&AGG_REDIS_TYPE)?.is_some() {
+        return Err(RedisError::Str("key already exist"));
+    }
+    let mut table = parse_state(&args[2])?;
+    table.validate()?;
+    table.restore();
+    table.name = args[1].clone();
+    table.set_db(ctx.selected_db());
+    key.set_value(&AGG_REDIS_TYPE, table)?;
+    let table = key
+        .get_value::<AggTable>(&AGG_REDIS_TYPE)?
+        .ok_or(RedisError::Str("impossible"))?;
+    register_table(table);
+    schedule(ctx);
+    ctx.replicate_verbatim();
+    REDIS_OK
+}
+
+fn add_view(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() <= 2 {
+        return Err(RedisError::WrongArity);
+    }
+    ctx.auto_memory();
+    let key = ctx.open_key_writable(&args[1]);
+    match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+        None => Err(RedisError::Str("key not exist")),
+        Some(v) => {
+            let result = v.add_view(&args[1], &args[2..])?;
+            ctx.replicate_verbatim();
+            Ok(result)
         }
-        Ok(RedisValue::SimpleString(id.into()))
     }
+}
 
-    pub fn save(&self, ctx: &Context) -> Result<(), RedisError> {
-        for view in &self.views {
-            view.save(ctx)?;
+fn insert_data(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() <= 3 {
+        return Err(RedisError::WrongArity);
+    }
+    ctx.auto_memory();
+    let key = ctx.open_key_writable(&args[1]);
+    match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+        None => Err(RedisError::Str("key not exist")),
+        Some(v) => {
+            let result = v.update(ctx, &args[1], &args[2], &args[3..])?;
+            ctx.replicate_verbatim();
+            Ok(result)
         }
-        Ok(())
     }
 }
 
-//////////////////////////////////////////////////////
-
-#[allow(non_snake_case, unused)]
-unsafe extern "C" fn agg_rdb_load(rdb: *mut raw::RedisModuleIO, encver: c_int) -> *mut c_void {
-    let table = serde_json::from_str::<AggTable>(&raw::load_string(rdb)).unwrap();
-    let table = Box::new(table);
-    Box::into_raw(table) as *mut c_void
+fn insert_batch(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() <= 2 {
+        return Err(RedisError::WrongArity);
+    }
+    ctx.auto_memory();
+    let key = ctx.open_key_writable(&args[1]);
+    match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+        None => Err(RedisError::Str("key not exist")),
+        Some(v) => {
+            let result = v.update_batch(ctx, &args[1], &args[2..])?;
+            ctx.replicate_verbatim();
+            Ok(result)
+        }
+    }
 }
 
-#[allow(non_snake_case, unused)]
-#[no_mangle]
-unsafe extern "C" fn agg_free(value: *mut c_void) {
-    Box::from_raw(value as *mut AggTable);
+fn insert_map(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() <= 2 {
+        return Err(RedisError::WrongArity);
+    }
+    ctx.auto_memory();
+    let key = ctx.open_key_writable(&args[1]);
+    match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+        None => Err(RedisError::Str("key not exist")),
+        Some(v) => {
+            let result = v.update_map(ctx, &args[1], &args[2], &args[3..])?;
+            ctx.replicate_verbatim();
+            Ok(result)
+        }
+    }
 }
 
-#[allow(non_snake_case, unused)]
-#[no_mangle]
-unsafe extern "C" fn agg_rdb_save(rdb: *mut raw::RedisModuleIO, value: *mut c_void) {
-    let table: &AggTable = mem::transmute(value);
-    raw::save_string(rdb, &serde_json::to_string(table).unwrap());
+fn set_source(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() <= 5 {
+        return Err(RedisError::WrongArity);
+    }
+    if args[2].to_lowercase() != "stream" || args[4].to_lowercase() != "fields" {
+        return Err(RedisError::Str("syntax error"));
+    }
+    ctx.auto_memory();
+    let key = ctx.open_key_writable(&args[1]);
+    match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+        None => Err(RedisError::Str("key not exist")),
+        Some(v) => {
+            let result = v.set_source(ctx, &args[1], &args[3], &args[5..])?;
+            ctx.replicate_verbatim();
+            Ok(result)
+        }
+    }
 }
 
-pub(crate) static AGG_REDIS_TYPE: RedisType = RedisType::new(
-    "aggre-hy1",
-    1,
-    raw::RedisModuleTypeMethods {
-        version: raw::REDISMODULE_TYPE_METHOD_VERSION as u64,
-
-        rdb_load: Some(agg_rdb_load),
-        rdb_save: Some(agg_rdb_save),
-        aof_rewrite: None,
-        free: Some(agg_free),
+fn alter_table(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() <= 3 {
+        return Err(RedisError::WrongArity);
+    }
+    let action = args[2].to_lowercase();
+    let default = match &args[4..] {
+        [] => None,
+        [option, value] if action == "add" && option.to_lowercase() == "default" => {
+            Some(parse_float(value)?)
+        }
+        _ => return Err(RedisError::Str("syntax error")),
+    };
+    ctx.auto_memory();
+    let key = ctx.open_key_writable(&args[1]);
+    match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+        None => Err(RedisError::Str("key not exist")),
+        Some(v) => {
+            let result = match action.as_str() {
+                "add" => v.add_column(&args[3], default)?,
+                "drop" => v.drop_column(&args[3])?,
+                _ => return Err(RedisError::Str("syntax error")),
+            };
+            ctx.replicate_verbatim();
+            Ok(result)
+        }
+    }
+}
 
-        aux_load: None,
-        aux_save: None,
-        aux_save_triggers: 0,
+fn drop_view(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() != 3 && args.len() != 4 {
+        return Err(RedisError::WrongArity);
+    }
+    let delete_output = match args.get(3) {
+        None => false,
+        Some(option) if option.to_lowercase() == "deleteoutput" => true,
+        Some(_) => return Err(RedisError::Str("syntax error")),
+    };
+    ctx.auto_memory();
+    let key = ctx.open_key_writable(&args[1]);
+    match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+        None => Err(RedisError::Str("key not exist")),
+        Some(v) => {
+            let result = v.drop_view(ctx, &args[1], &args[2], delete_output)?;
+            ctx.replicate_verbatim();
+            Ok(result)
+        }
+    }
+}
 
-        // Currently unused by Redis
-        mem_usage: None,
-        digest: None,
-    },
-);
+fn rename_view(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() != 4 {
+        return Err(RedisError::WrongArity);
+    }
+    ctx.auto_memory();
+    let key = ctx.open_key_writable(&args[1]);
+    match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+        None => Err(RedisError::Str("key not exist")),
+        Some(v) => {
+            let result = v.rename_view(ctx, &args[1], &args[2], &args[3])?;
+            ctx.replicate_verbatim();
+            Ok(result)
+        }
+    }
+}
 
-fn timer_callback(ctx: &Context, name: String) -> () {
+fn reset_view(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() != 3 {
+        return Err(RedisError::WrongArity);
+    }
     ctx.auto_memory();
-    let key = ctx.open_key_writable(&name);
-    match key.get_value::<AggTable>(&AGG_REDIS_TYPE).unwrap() {
-        Some(table) => {
-            table.timer =
-                ctx.create_timer(Duration::from_secs(TIMER_INTERVAL), timer_callback, name);
-            match table.save(&ctx) {
-                Ok(_) => {}
-                Err(err) => {
-                    println!("save failed: {:?}", err);
-                }
-            }
+    let key = ctx.open_key_writable(&args[1]);
+    match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+        None => Err(RedisError::Str("key not exist")),
+        Some(v) => {
+            let result = v.reset_view(&args[2])?;
+            ctx.replicate_verbatim();
+            Ok(result)
         }
-        None => {
-            println!("timer key not found");
+    }
+}
+
+fn rebuild_view(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() != 3 && args.len() != 5 {
+        return Err(RedisError::WrongArity);
+    }
+    let from = match args.get(3) {
+        None => None,
+        Some(option) if option.to_lowercase() == "from" => {
+            let ms = parse_integer(&args[4])?
+                .try_into()
+                .map_err(|err: TryFromIntError| RedisError::String(err.to_string()))?;
+            Some(ms)
         }
+        Some(_) => return Err(RedisError::Str("syntax error")),
     };
+    ctx.auto_memory();
+    let key = ctx.open_key_writable(&args[1]);
+    match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+        None => Err(RedisError::Str("key not exist")),
+        Some(v) => {
+            let result = v.rebuild_view(ctx, &args[1], &args[2], from)?;
+            ctx.replicate_verbatim();
+            Ok(result)
+        }
+    }
 }
 
-fn new_table(ctx: &Context, args: Vec<String>) -> RedisResult {
+fn merge_tables(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
     if args.len() <= 2 {
         return Err(RedisError::WrongArity);
     }
+    if args[2..].contains(&args[1]) {
+        return Err(RedisError::Str("dest can't be a source"));
+    }
     ctx.auto_memory();
+    let keys = args[2..]
+        .iter()
+        .map(|name| ctx.open_key(name))
+        .collect::<Vec<_>>();
+    let mut sources = Vec::with_capacity(keys.len());
+    for key in &keys {
+        match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+            None => return Err(RedisError::Str("key not exist")),
+            Some(v) => sources.push(v),
+        }
+    }
     let key = ctx.open_key_writable(&args[1]);
     match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
-        Some(_) => {
-            return Err(RedisError::Str("key already exist"));
-        }
-        None => {
-            key.set_value(&AGG_REDIS_TYPE, AggTable::new(args[2..].to_vec()))?;
-            let table = key
-                .get_value::<AggTable>(&AGG_REDIS_TYPE)?
-                .ok_or(RedisError::Str("impossible"))?;
-            table.timer = ctx.create_timer(
-                Duration::from_secs(TIMER_INTERVAL),
-                timer_callback,
-                args[1].clone(),
-            );
+        None => Err(RedisError::Str("key not exist")),
+        Some(v) => {
+            let result = v.merge_tables(ctx, &args[1], &sources)?;
+            ctx.replicate_verbatim();
+            Ok(result)
         }
     }
-    ctx.replicate_verbatim();
-    REDIS_OK
 }
 
-fn add_view(ctx: &Context, args: Vec<String>) -> RedisResult {
-    if args.len() <= 2 {
+fn export_state(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() != 2 {
+        return Err(RedisError::WrongArity);
+    }
+    ctx.auto_memory();
+    let key = ctx.open_key(&args[1]);
+    match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+        None => Err(RedisError::Str("key not exist")),
+        Some(v) => Ok(RedisValue::BulkString(v.export_state()?)),
+    }
+}
+
+fn import_state(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() != 3 {
         return Err(RedisError::WrongArity);
     }
     ctx.auto_memory();
@@ -737,30 +4501,135 @@ fn add_view(ctx: &Context, args: Vec<String>) -> RedisResult {
     match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
         None => Err(RedisError::Str("key not exist")),
         Some(v) => {
-            let result = v.add_view(&args[2..])?;
+            let result = v.import_state(&args[2])?;
             ctx.replicate_verbatim();
             Ok(result)
         }
     }
 }
 
-fn insert_data(ctx: &Context, args: Vec<String>) -> RedisResult {
-    if args.len() <= 3 {
+fn range_view(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() < 5 {
+        return Err(RedisError::WrongArity);
+    }
+    ctx.auto_memory();
+    let key = ctx.open_key(&args[1]);
+    match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+        None => Err(RedisError::Str("key not exist")),
+        Some(v) => v.range(ctx, &args[2], &args[3..]),
+    }
+}
+
+fn query_view(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() < 5 {
+        return Err(RedisError::WrongArity);
+    }
+    ctx.auto_memory();
+    let key = ctx.open_key(&args[1]);
+    match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+        None => Err(RedisError::Str("key not exist")),
+        Some(v) => v.query(ctx, &args[2], &args[3..]),
+    }
+}
+
+/// Parse the sql statement of the table key, the statement reads from the key only.
+fn parse_sql(key: &str, sql: &str) -> Result<SqlQuery, RedisError> {
+    let query = SqlParser::parse(sql)?;
+    if query.table != key {
+        return Err(RedisError::Str("FROM must be the key"));
+    }
+    Ok(query)
+}
+
+fn run_sql(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() != 3 {
+        return Err(RedisError::WrongArity);
+    }
+    let query = parse_sql(&args[1], &args[2])?;
+    if query.view.is_some() {
+        return Err(RedisError::Str("CREATE VIEW is run by agg.sqlview"));
+    }
+    ctx.auto_memory();
+    let key = ctx.open_key(&args[1]);
+    match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+        None => Err(RedisError::Str("key not exist")),
+        Some(v) => v.sql(&args[1], &query),
+    }
+}
+
+fn create_sql_view(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() != 3 {
         return Err(RedisError::WrongArity);
     }
+    let query = parse_sql(&args[1], &args[2])?;
+    let name = query
+        .view
+        .as_deref()
+        .ok_or(RedisError::Str("SELECT is run by agg.sql"))?;
     ctx.auto_memory();
     let key = ctx.open_key_writable(&args[1]);
     match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
         None => Err(RedisError::Str("key not exist")),
         Some(v) => {
-            let result = v.update(ctx, &args[2], &args[3..])?;
+            let result = v.sql_view(&args[1], name, &query)?;
             ctx.replicate_verbatim();
             Ok(result)
         }
     }
 }
 
-fn dump_table(ctx: &Context, args: Vec<String>) -> RedisResult {
+fn list_tables(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    let pattern = match &args[1..] {
+        [] => None,
+        [option, pattern] if option.to_lowercase() == "match" => Some(pattern.as_bytes()),
+        _ => return Err(RedisError::Str("syntax error")),
+    };
+    let db = ctx.selected_db();
+    let entries = TABLES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, entry)| entry.db == Some(db))
+        .filter(|(_, entry)| pattern.map_or(true, |p| glob_match(p, entry.name.as_bytes())))
+        .map(|(&serial, entry)| (serial, entry.name.clone()))
+        .collect::<Vec<_>>();
+    let mut result = Vec::new();
+    for (serial, name) in entries {
+        let key = ctx.open_key(&name);
+        if let Ok(Some(table)) = key.get_value::<AggTable>(&AGG_REDIS_TYPE) {
+            if table.serial == serial {
+                result.push((name, table.fields.len(), table.views.len()));
+            }
+        }
+    }
+    result.sort();
+    Ok(RedisValue::Array(
+        result
+            .into_iter()
+            .map(|(name, columns, views)| {
+                RedisValue::Array(vec![
+                    RedisValue::SimpleString(name),
+                    RedisValue::Integer(columns as i64),
+                    RedisValue::Integer(views as i64),
+                ])
+            })
+            .collect(),
+    ))
+}
+
+fn table_info(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() != 2 {
+        return Err(RedisError::WrongArity);
+    }
+    ctx.auto_memory();
+    let key = ctx.open_key(&args[1]);
+    match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
+        None => Err(RedisError::Str("key not exist")),
+        Some(v) => Ok(v.info()),
+    }
+}
+
+fn dump_table(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
     if args.len() <= 1 {
         return Err(RedisError::WrongArity);
     }
@@ -775,23 +4644,23 @@ fn dump_table(ctx: &Context, args: Vec<String>) -> RedisResult {
     }
 }
 
-fn save_table(ctx: &Context, args: Vec<String>) -> RedisResult {
+fn save_table(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
     if args.len() != 2 {
         return Err(RedisError::WrongArity);
     }
     ctx.auto_memory();
 
-    let key = ctx.open_key(&args[1]);
+    let key = ctx.open_key_writable(&args[1]);
     match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
         None => Err(RedisError::Str("key not exist")),
         Some(v) => {
-            v.save(ctx)?;
+            v.save(ctx, &args[1])?;
             REDIS_OK
         }
     }
 }
 
-fn get_last_id(ctx: &Context, args: Vec<String>) -> RedisResult {
+fn get_last_id(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
     if args.len() != 2 {
         return Err(RedisError::WrongArity);
     }
@@ -803,10 +4672,15 @@ fn get_last_id(ctx: &Context, args: Vec<String>) -> RedisResult {
     }
 }
 
-fn get_current_value(ctx: &Context, args: Vec<String>) -> RedisResult {
-    if args.len() != 2 {
+fn get_current_value(ctx: &ModuleContext, args: Vec<String>) -> RedisResult {
+    if args.len() != 2 && args.len() != 3 {
         return Err(RedisError::WrongArity);
     }
+    let with_names = match args.get(2) {
+        None => false,
+        Some(arg) if arg.to_lowercase() == "withnames" => true,
+        Some(_) => return Err(RedisError::Str("syntax error")),
+    };
 
     let key = ctx.open_key(&args[1]);
     match key.get_value::<AggTable>(&AGG_REDIS_TYPE)? {
@@ -816,6 +4690,9 @@ fn get_current_value(ctx: &Context, args: Vec<String>) -> RedisResult {
             for view in &v.views {
                 let mut items = Vec::new();
                 for field in &view.fields {
+                    if with_names {
+                        items.push(RedisValue::SimpleString(field.name.clone()));
+                    }
                     items.push(
                         field
                             .op
@@ -836,19 +4713,146 @@ fn get_current_value(ctx: &Context, args: Vec<String>) -> RedisResult {
     }
 }
 
-redis_module! {
-    name: "aggregate",
-    version: 1,
-    data_types: [
-        AGG_REDIS_TYPE,
-    ],
-    commands: [
-        ["agg.new", new_table, "write", 1, 1, 1],
-        ["agg.view", add_view, "write", 1, 1, 1],
-        ["agg.insert", insert_data, "write", 1, 1, 1],
-        ["agg.save", save_table, "write", 1, 1, 1],
-        ["agg.dump", dump_table, "readonly", 1, 1, 1],
-        ["agg.last_id", get_last_id, "readonly", 1, 1, 1],
-        ["agg.current", get_current_value, "readonly", 1, 1, 1],
-    ],
+fn init(ctx: &ModuleContext, args: &[String]) -> raw::Status {
+    for chunk in args.chunks(2) {
+        match (chunk[0].to_lowercase().as_str(), chunk.get(1)) {
+            ("save_interval_ms", Some(ms)) => match ms.parse::<u64>() {
+                Ok(ms) if ms > 0 => SAVE_INTERVAL.store(ms, Ordering::Relaxed),
+                _ => {
+                    ctx.log(LogLevel::Warning, "SAVE_INTERVAL_MS must be positive");
+                    return raw::Status::Err;
+                }
+            },
+            _ => {
+                ctx.log(
+                    LogLevel::Warning,
+                    &format!("unknown module argument {}", chunk[0]),
+                );
+                return raw::Status::Err;
+            }
+        }
+    }
+    let types = raw::REDISMODULE_NOTIFY_STREAM | raw::REDISMODULE_NOTIFY_GENERIC;
+    unsafe {
+        raw::RedisModule_SubscribeToKeyspaceEvents.unwrap()(
+            ctx.raw,
+            types as c_int,
+            Some(on_keyspace_event),
+        );
+        let loading = raw::RedisModuleEvent {
+            id: raw::REDISMODULE_EVENT_LOADING as u64,
+            dataver: 1,
+        };
+        raw::subscribe_to_server_event(ctx.raw, loading, Some(on_loading));
+    }
+    raw::Status::Ok
+}
+
+/// Decode the arguments of a command or the module.
+fn module_args(
+    argv: *mut *mut raw::RedisModuleString,
+    argc: c_int,
+) -> Result<Vec<String>, RedisError> {
+    if argc <= 0 {
+        return Ok(Vec::new());
+    }
+    unsafe { slice::from_raw_parts(argv, argc as usize) }
+        .iter()
+        .map(|&arg| {
+            RedisString::from_ptr(arg)
+                .map(|s| s.to_owned())
+                .map_err(|_| RedisError::Str("UTF8 encoding error in args"))
+        })
+        .collect()
+}
+
+/// Register a command like `redis_command!`, the handler gets a `ModuleContext`.
+macro_rules! agg_command {
+    ($ctx:expr, $name:expr, $handler:expr, $flags:expr, $firstkey:expr, $lastkey:expr, $keystep:expr) => {{
+        extern "C" fn do_command(
+            ctx: *mut raw::RedisModuleCtx,
+            argv: *mut *mut raw::RedisModuleString,
+            argc: c_int,
+        ) -> c_int {
+            let ctx = ModuleContext::new(ctx);
+            let response = module_args(argv, argc).and_then(|args| $handler(&ctx, args));
+            ctx.reply(response) as c_int
+        }
+        let name = CString::new($name).unwrap();
+        let flags = CString::new($flags).unwrap();
+        let status = unsafe {
+            raw::RedisModule_CreateCommand.unwrap()(
+                $ctx,
+                name.as_ptr(),
+                Some(do_command),
+                flags.as_ptr(),
+                $firstkey,
+                $lastkey,
+                $keystep,
+            )
+        };
+        if status == raw::Status::Err as c_int {
+            return raw::Status::Err as c_int;
+        }
+    }};
+}
+
+/// Module entry, like `redis_module!`, which doesn't pass the module arguments to the init
+/// function in redis-module 0.10.
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn RedisModule_OnLoad(
+    ctx: *mut raw::RedisModuleCtx,
+    argv: *mut *mut raw::RedisModuleString,
+    argc: c_int,
+) -> c_int {
+    // nothing can be allocated before the init, the allocator of the module is redis
+    let name = b"aggregate\0";
+    if unsafe {
+        raw::Export_RedisModule_Init(
+            ctx,
+            name.as_ptr() as *const c_char,
+            1,
+            raw::REDISMODULE_APIVER_1 as c_int,
+        )
+    } == raw::Status::Err as c_int
+    {
+        return raw::Status::Err as c_int;
+    }
+    let args = match module_args(argv, argc) {
+        Ok(args) => args,
+        Err(_) => return raw::Status::Err as c_int,
+    };
+    if init(&ModuleContext::new(ctx), &args) == raw::Status::Err {
+        return raw::Status::Err as c_int;
+    }
+    if AGG_REDIS_TYPE.create_data_type(ctx).is_err() {
+        return raw::Status::Err as c_int;
+    }
+    agg_command!(ctx, "agg.new", new_table, "write", 1, 1, 1);
+    agg_command!(ctx, "agg.restore", restore_table, "write", 1, 1, 1);
+    agg_command!(ctx, "agg.view", add_view, "write", 1, 1, 1);
+    agg_command!(ctx, "agg.insert", insert_data, "write", 1, 1, 1);
+    agg_command!(ctx, "agg.minsert", insert_batch, "write", 1, 1, 1);
+    agg_command!(ctx, "agg.insertmap", insert_map, "write", 1, 1, 1);
+    agg_command!(ctx, "agg.source", set_source, "write", 1, 1, 1);
+    agg_command!(ctx, "agg.save", save_table, "write", 1, 1, 1);
+    agg_command!(ctx, "agg.alter", alter_table, "write", 1, 1, 1);
+    agg_command!(ctx, "agg.dropview", drop_view, "write", 1, 1, 1);
+    agg_command!(ctx, "agg.renameview", rename_view, "write", 1, 1, 1);
+    agg_command!(ctx, "agg.resetview", reset_view, "write", 1, 1, 1);
+    agg_command!(ctx, "agg.rebuild", rebuild_view, "write", 1, 1, 1);
+    agg_command!(ctx, "agg.merge", merge_tables, "write", 1, -1, 1);
+    agg_command!(ctx, "agg.export", export_state, "readonly", 1, 1, 1);
+    agg_command!(ctx, "agg.import", import_state, "write", 1, 1, 1);
+    agg_command!(ctx, "agg.info", table_info, "readonly", 1, 1, 1);
+    agg_command!(ctx, "agg.list", list_tables, "readonly", 0, 0, 0);
+    agg_command!(ctx, "agg.range", range_view, "readonly", 1, 1, 1);
+    agg_command!(ctx, "agg.query", query_view, "readonly", 1, 1, 1);
+    agg_command!(ctx, "agg.sql", run_sql, "readonly", 1, 1, 1);
+    agg_command!(ctx, "agg.sqlview", create_sql_view, "write", 1, 1, 1);
+    agg_command!(ctx, "agg.dump", dump_table, "readonly", 1, 1, 1);
+    agg_command!(ctx, "agg.last_id", get_last_id, "readonly", 1, 1, 1);
+    agg_command!(ctx, "agg.current", get_current_value, "readonly", 1, 1, 1);
+    raw::Status::Ok as c_int
 }